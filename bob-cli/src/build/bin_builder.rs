@@ -1,39 +1,228 @@
 use anyhow::{anyhow, Context};
-use bob_lib::dirhasher;
-use log::info;
-use std::io::Write;
-use std::path::Path;
-use std::{env, fs, path::PathBuf, process};
+use bob_lib::{dirhasher, source_files};
+use log::{info, warn};
+use rapidhash::RapidInlineHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash as _, Hasher as _};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path};
+use std::{env, fs, path::PathBuf};
 
-use crate::config::{BobConfig, BuilderConfigVariant};
+use super::{
+    interrupt::{self, TempPath},
+    output::ProjectOutput,
+    runtime::{image_tag, ContainerRuntime, ImageBuild, ResourceLimits},
+    summary::BuildStep,
+};
+use crate::buildinfo::CacheKey;
+use crate::config::{BobConfig, BuilderConfigVariant, SecretSource};
 
-fn generate_dockerfile(
-    variant: &BuilderConfigVariant,
+/// The values a project's Dockerfile template is rendered with, its `build_args` are available
+/// as `build_args`
+pub fn template_values(build_config: &BobConfig) -> anyhow::Result<serde_value::Value> {
+    let variant = &build_config.builder_config;
+    let values = match variant {
+        BuilderConfigVariant::Custom(custom) => serde_value::to_value(&custom.values),
+        _ => serde_value::to_value(variant.get_inner_as_generic()),
+    }
+    .context("Couldn't serialize template values")?;
+    if build_config.build_args.is_empty() {
+        return Ok(values);
+    }
+
+    let values = match values {
+        serde_value::Value::Option(x) => x.map_or(serde_value::Value::Map(BTreeMap::new()), |x| *x),
+        x => x,
+    };
+    let serde_value::Value::Map(mut values) = values else {
+        return Err(anyhow!(
+            "Custom values must be a table when using build_args"
+        ));
+    };
+    values.insert(
+        serde_value::Value::String("build_args".to_owned()),
+        serde_value::to_value(&build_config.build_args).context("Couldn't serialize build args")?,
+    );
+    Ok(serde_value::Value::Map(values))
+}
+
+pub fn generate_dockerfile(
+    build_config: &BobConfig,
     project_root: &Path,
 ) -> anyhow::Result<String> {
     let mut tt = tinytemplate::TinyTemplate::new();
-    let generic = variant.get_inner_as_generic();
+    let generic = build_config.builder_config.get_inner_as_generic();
     let contents = generic.get_dockerfile_contents(project_root)?;
     tt.add_template("x", &contents)
         .context("Dockerfile was not a valid tinytemplate")?;
-    match variant {
-        BuilderConfigVariant::Custom(custom) => {
-            return tt.render("x", &custom.values).map_err(Into::into)
+    tt.render("x", &template_values(build_config)?)
+        .map_err(Into::into)
+}
+
+pub struct BuildResult {
+    pub cache_key: CacheKey,
+}
+
+/// Errors instead of reading more than `remaining` bytes
+struct SizeLimitedReader<'a> {
+    inner: &'a mut dyn Read,
+    remaining: u64,
+    limit: u64,
+}
+
+impl Read for SizeLimitedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading one byte past the limit tells us if the output is exactly `limit` bytes long
+        let max = usize::try_from(self.remaining.saturating_add(1)).unwrap_or(usize::MAX);
+        let max = buf.len().min(max);
+        let read = self.inner.read(&mut buf[..max])?;
+        if read as u64 > self.remaining {
+            return Err(io::Error::other(format!(
+                "container output is bigger than the max output size of {} bytes",
+                self.limit
+            )));
         }
-        _ => tt.render("x", &generic).map_err(Into::into),
+        self.remaining -= read as u64;
+        Ok(read)
     }
 }
 
-pub struct BuildResult {
-    pub tar_binary: Vec<u8>,
-    pub dir_hash: u64,
+fn hash_str(s: &str) -> u64 {
+    let mut hasher = RapidInlineHasher::default();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Returns the cache key of a project together with the rendered Dockerfile
+pub fn cache_key(
+    project_root: &Path,
+    build_config: &BobConfig,
+) -> anyhow::Result<(CacheKey, String)> {
+    let dockerfile_content =
+        generate_dockerfile(build_config, project_root).context("Generating dockerfile")?;
+    // Limits don't change what is built
+    let serialized_config = toml::to_string(&BobConfig {
+        timeout: None,
+        cpus: None,
+        memory: None,
+        ..build_config.clone()
+    })
+    .context("Serializing project config")?;
+
+    let cache_key = CacheKey {
+        source: dirhasher(project_root.to_owned())?,
+        dockerfile: hash_str(&dockerfile_content),
+        config: hash_str(&serialized_config),
+        bob_version: env!("CARGO_PKG_VERSION").to_owned(),
+    };
+
+    Ok((cache_key, dockerfile_content))
+}
+
+/// Checks how secrets are declared, without looking at their values or files: ids must be
+/// usable in `--secret id=...` and files must be outside of the project. `project_root` must be
+/// canonical
+pub fn check_secret_declarations(
+    project_root: &Path,
+    secrets: &BTreeMap<String, SecretSource>,
+) -> anyhow::Result<()> {
+    for (id, source) in secrets {
+        let valid_id = !id.is_empty()
+            && id
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || matches!(x, '_' | '-' | '.'));
+        if !valid_id {
+            return Err(anyhow!(
+                "Secret id {id:?} may only contain letters, digits, `_`, `-` and `.`"
+            ));
+        }
+        if let SecretSource::File(path) = source {
+            if normalize_lexically(&project_root.join(path)).starts_with(project_root) {
+                return Err(anyhow!(
+                    "Secret {id:?} comes from file {path:?}, which must be outside of the project"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `path` with `.` and `..` resolved without looking at the filesystem
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            x => normalized.push(x),
+        }
+    }
+    normalized
+}
+
+/// Makes sure all secrets are declared correctly and available before starting a build, without
+/// reading them. `project_root` must be canonical
+pub fn check_secrets(
+    project_root: &Path,
+    secrets: &BTreeMap<String, SecretSource>,
+) -> anyhow::Result<()> {
+    check_secret_declarations(project_root, secrets)?;
+    for (id, source) in secrets {
+        match source {
+            SecretSource::Env(var) if env::var_os(var).is_none() => {
+                return Err(anyhow!(
+                    "Secret {id:?} comes from env var {var:?}, which isn't set"
+                ))
+            }
+            SecretSource::File(path) => {
+                let full_path = project_root
+                    .join(path)
+                    .canonicalize()
+                    .ok()
+                    .filter(|x| x.is_file())
+                    .ok_or_else(|| {
+                        anyhow!("Secret {id:?} comes from file {path:?}, which doesn't exist")
+                    })?;
+                // Otherwise it would be hashed into the cache key and sent as build context.
+                // Also checked here, a symlink can point into the project
+                if full_path.starts_with(project_root) {
+                    return Err(anyhow!(
+                        "Secret {id:?} comes from file {path:?}, which must be outside of the project"
+                    ));
+                }
+            }
+            SecretSource::Env(_) => {}
+        }
+    }
+    Ok(())
 }
 
-mod uid {
+/// Fills `context_dir` with the files of the project that are hashed for its cache key, so the
+/// build can't depend on anything else and gitignored junk isn't sent to the daemon
+fn stage_build_context(project_root: &Path, context_dir: &Path) -> anyhow::Result<()> {
+    for path in source_files(project_root)? {
+        let src = project_root.join(&path);
+        let dst = context_dir.join(&path);
+        fs::create_dir_all(dst.parent().unwrap()).context(format!(
+            "Couldn't create {:?} in build context",
+            path.parent()
+        ))?;
+        // Hardlinks are free, but don't work across filesystems. A hardlink to a symlink would
+        // keep its target relative to the new location, so those are copied through
+        if src.is_symlink() || fs::hard_link(&src, &dst).is_err() {
+            fs::copy(&src, &dst).context(format!("Couldn't copy {path:?} to build context"))?;
+        }
+    }
+    Ok(())
+}
+
+pub(super) mod uid {
     use std::sync::atomic::{AtomicUsize, Ordering};
 
     pub static COUNTER: AtomicUsize = AtomicUsize::new(0);
-    pub(super) fn uid() -> Box<str> {
+    pub(in crate::build) fn uid() -> Box<str> {
         format!(
             "{}-{}",
             std::process::id(),
@@ -44,79 +233,128 @@ mod uid {
 }
 use uid::uid;
 
-// Returns Ok(None) if hash matches
+pub struct BuildOptions<'a> {
+    pub runtime: &'a dyn ContainerRuntime,
+    /// Canonical path of the root bob.toml, images are labeled with it
+    pub config_path: &'a Path,
+    pub output: &'a ProjectOutput,
+    /// Rebuild even if the cache key matches
+    pub force: bool,
+    /// Remove the image of the previous build once the new one is done
+    pub prune_images: bool,
+    /// Used for the limits a project doesn't set itself
+    pub default_limits: ResourceLimits,
+    pub max_output_size: u64,
+}
+
+// Returns Ok(None) if cache key matches, otherwise the tar output of the container is streamed
+// into `extract`
 pub fn build(
     project_root: PathBuf,
     build_config: &BobConfig,
-    prev_hash: Option<u64>,
+    prev_cache_key: Option<&CacheKey>,
+    options: &BuildOptions,
+    extract: &mut dyn FnMut(&mut dyn Read) -> anyhow::Result<()>,
 ) -> anyhow::Result<Option<BuildResult>> {
     let project_root = project_root.canonicalize()?;
 
-    let dockerfile_content = generate_dockerfile(&build_config.builder_config, &project_root)
-        .context("Generating dockerfile")?;
-    let tempfile_path = env::temp_dir().join(format!("Dockerfile-{}", uid()));
-
-    let mut tempfile = fs::File::create_new(&tempfile_path)?;
-    tempfile.write_all(dockerfile_content.as_bytes())?;
-    drop(tempfile);
-
-    let hash = dirhasher(project_root.clone())?;
+    let (cache_key, dockerfile_content) = cache_key(&project_root, build_config)?;
+    let hash = cache_key.combined();
 
-    info!("{project_root:?} - hash: {hash:X}");
+    let project_name = &build_config.project_name;
+    info!("[{project_name}] {project_root:?} - hash: {hash:X}");
 
-    if Some(hash) == prev_hash {
-        info!("Old hash matched, wont rebuild");
+    let mut changes = cache_key.changes_since(prev_cache_key);
+    if options.force {
+        changes.insert(0, "forced".to_owned());
+    }
+    if changes.is_empty() {
+        info!("[{project_name}] Old cache key matched, wont rebuild");
         return Ok(None);
     }
 
-    info!("No hash match, building");
-
-    let docker_tag = format!("bob_build:{:x}", hash);
-
-    let build_status_code = process::Command::new("docker")
-        .args([
-            "build",
-            "-f",
-            tempfile_path.to_str().unwrap(),
-            "-t",
-            &docker_tag,
-            ".",
-        ])
-        .stdout(process::Stdio::inherit())
-        .stderr(process::Stdio::inherit())
-        .current_dir(&project_root)
-        .status()?;
-
-    if !build_status_code.success() {
-        return Err(anyhow!(
-            "Docker build failed for bob project {:?}",
-            build_config.project_name
-        ));
+    info!("[{project_name}] Rebuilding: {}", changes.join(", "));
+
+    check_secrets(&project_root, &build_config.secrets).context(BuildStep::Prepare)?;
+
+    // Removed when this function returns, no matter how
+    let tempfile_path = TempPath::new(env::temp_dir().join(format!("Dockerfile-{}", uid())));
+    fs::File::create_new(&tempfile_path)
+        .and_then(|mut x| x.write_all(dockerfile_content.as_bytes()))
+        .context("Couldn't write temp Dockerfile")
+        .context(BuildStep::Prepare)?;
+
+    let docker_tag = image_tag(hash);
+
+    let BuildOptions {
+        runtime,
+        output,
+        max_output_size,
+        ..
+    } = *options;
+
+    // Relative secret files would otherwise be looked up in the build context
+    let secrets = build_config
+        .secrets
+        .iter()
+        .map(|(id, source)| {
+            let source = match source {
+                SecretSource::File(path) => SecretSource::File(project_root.join(path)),
+                x => x.clone(),
+            };
+            (id.clone(), source)
+        })
+        .collect();
+
+    let limits = build_config
+        .resource_limits()
+        .or(options.default_limits)
+        .start();
+
+    let context_dir = TempPath::new(env::temp_dir().join(format!("bob-context-{}", uid())));
+    stage_build_context(&project_root, &context_dir).context(BuildStep::Prepare)?;
+    interrupt::check().context(BuildStep::Prepare)?;
+
+    runtime
+        .build_image(
+            &ImageBuild {
+                context_dir: &context_dir,
+                dockerfile_path: &tempfile_path,
+                tag: &docker_tag,
+                project_name,
+                config_path: options.config_path,
+                build_args: &build_config.build_args,
+                secrets: &secrets,
+                limits: &limits,
+            },
+            output,
+        )
+        .context(BuildStep::BuildImage)?;
+    drop(context_dir);
+    interrupt::check().context(BuildStep::BuildImage)?;
+
+    runtime
+        .run_image(&docker_tag, output, &limits, &mut |stdout| {
+            extract(&mut SizeLimitedReader {
+                inner: stdout,
+                remaining: max_output_size,
+                limit: max_output_size,
+            })
+        })
+        .context(BuildStep::RunImage)?;
+
+    if options.prune_images {
+        if let Some(prev_cache_key) = prev_cache_key {
+            let prev_docker_tag = image_tag(prev_cache_key.combined());
+            if prev_docker_tag != docker_tag {
+                if let Err(e) = runtime.remove_image(&prev_docker_tag) {
+                    warn!("[{project_name}] Couldn't remove old image {prev_docker_tag}: {e:#}");
+                }
+            }
+        }
     }
 
-    let bin = process::Command::new("docker")
-        .args(["run", "--rm", &docker_tag])
-        .stderr(process::Stdio::inherit())
-        .current_dir(&project_root)
-        .output()?
-        .stdout;
-
-    // let docker_rm_status_code = process::Command::new("docker")
-    //     .args(&["image", "rm", &docker_tag])
-    //     .stdout(process::Stdio::inherit())
-    //     .stderr(process::Stdio::inherit())
-    //     .status()?;
-
-    // if !docker_rm_status_code.success() {
-    //     warn!("Couldn't remove docker image {:?}", docker_tag)
-    // }
-
-    fs::remove_file(tempfile_path).context("removing tmp dockerfile")?;
-
-    Ok(Some(BuildResult {
-        tar_binary: bin,
-        dir_hash: hash,
-    }))
+    Ok(Some(BuildResult { cache_key }))
 
     // todo!()
 }
@@ -0,0 +1,88 @@
+use log::warn;
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Once,
+    },
+};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+static HANDLER_INSTALLED: Once = Once::new();
+
+/// Makes the first Ctrl-C stop builds gracefully, the second one exits right away. Only installs
+/// the handler on the first call, there can only be one per process
+pub fn install_handler() -> anyhow::Result<()> {
+    let mut result = Ok(());
+    HANDLER_INSTALLED.call_once(|| {
+        result = ctrlc::set_handler(|| {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(130);
+            }
+            warn!(
+                "Interrupted, stopping builds and cleaning up. Press Ctrl-C again to quit right away"
+            );
+        });
+    });
+    Ok(result?)
+}
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Error for builds stopped by Ctrl-C, they are reported as cancelled instead of failed
+#[derive(Debug, Clone, Copy)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Errors with [`Interrupted`] if Ctrl-C was pressed
+pub fn check() -> anyhow::Result<()> {
+    if is_interrupted() {
+        return Err(Interrupted.into());
+    }
+    Ok(())
+}
+
+/// A temp file or dir that is removed when dropped, also when a build fails or is interrupted
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(path: PathBuf) -> Self {
+        Self(path)
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
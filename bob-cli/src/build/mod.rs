@@ -1,8 +1,15 @@
 use std::{
+    collections::BTreeMap,
     fs,
-    io::{Cursor, Read as _, Write as _},
-    path::{Path, PathBuf},
+    io::{self, Read, Write as _},
+    path::{Component, Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 #[cfg(unix)]
@@ -10,20 +17,49 @@ use std::os::unix::fs::PermissionsExt;
 
 use crate::{
     buildinfo::{BuildInfo, Project},
-    config::read_build_configs,
+    config::{read_build_configs, read_root_config, BobConfig},
+    platform::{Arch, Os, Platform, TargetTriple},
     BuildCommand,
 };
 use anyhow::{anyhow, Context as _};
-use log::info;
+use bin_builder::BuildOptions;
+use log::{error, info, warn};
+use output::ProjectOutput;
+use runtime::{ContainerRuntime, ResourceLimits};
+use summary::{print_summary, BuildStep, Outcome, ProjectReport};
 
-mod bin_builder;
+pub mod bin_builder;
+mod interrupt;
+mod output;
+mod plan;
+pub mod runtime;
+mod summary;
 
 pub const BUILDINFO_PATH_RELATIVE: &str = "./buildinfo.toml";
+/// Projects are built in here first and only moved to their real location once done
+pub const STAGING_PATH_RELATIVE: &str = "./.staging";
+/// Previous outputs are moved in here while being replaced, see [`replace_dir`]
+pub const OLD_PATH_RELATIVE: &str = "./.old";
+/// Container output of every project's last build, see [`log_path_relative`]
+pub const LOGS_PATH_RELATIVE: &str = "./.logs";
+
+/// How many bytes at the start of a built file are used to detect if it's an executable
+const FILE_TYPE_DETECTION_BYTES: u64 = 8192;
 
 pub fn command_build(build_command: BuildCommand) -> anyhow::Result<()> {
     if !fs::exists(&build_command.config_path)? {
         return Err(anyhow!("File doesn't exist"));
     }
+    let config_path = fs::canonicalize(&build_command.config_path)
+        .context(format!("Couldn't resolve {:?}", build_command.config_path))?;
+
+    let root_config = read_root_config(&build_command.config_path)?;
+    let runtime_kind = build_command
+        .runtime
+        .or(root_config.runtime)
+        .unwrap_or_default();
+    info!("Using container runtime {runtime_kind:?}");
+    let runtime = runtime_kind.instantiate();
 
     let build_configs = read_build_configs(build_command.config_path.clone())?;
 
@@ -32,123 +68,634 @@ pub fn command_build(build_command: BuildCommand) -> anyhow::Result<()> {
         .ok()
         .and_then(|s| BuildInfo::from_str(&s).ok());
 
-    let mut build_info = BuildInfo::new();
-
-    for (bob_toml_path, build_config) in build_configs {
-        let proj_src_root_dir = bob_toml_path
-            .canonicalize()
-            .context("bob config parent dir doesn't exist")?
-            .parent()
-            .ok_or(anyhow!("couldn't get parent dir of bob config"))?
-            .canonicalize()?;
-        drop(bob_toml_path);
-
-        let proj_build_root_dir = build_command.out_dir.join(&build_config.project_name);
-
-        let prev_project_info = proj_build_root_dir
-            .exists()
-            .then(|| {
-                build_info_prev.as_ref().and_then(|x| {
-                    x.projects
-                        .iter()
-                        .find(|x| x.name == build_config.project_name)
-                })
-            })
-            .flatten();
+    let ctx = BuildContext {
+        config_path: &config_path,
+        out_dir: &build_command.out_dir,
+        build_info_prev: build_info_prev.as_ref(),
+        runtime: runtime.as_ref(),
+        max_output_size: build_command.max_output_size,
+        max_output_entries: build_command.max_output_entries,
+        force: build_command.force,
+        prune_images: build_command.prune_images,
+        default_limits: ResourceLimits::new(
+            build_command.timeout.map(|x| x.0),
+            build_command.cpus,
+            build_command.memory,
+        ),
+    };
 
-        if let Some(bin_build_result) = bin_builder::build(
-            proj_src_root_dir.to_owned(),
-            &build_config,
-            prev_project_info.map(|x| x.hash),
-        )
-        .context(format!(
-            "Failed to build binaries for project with root at {proj_src_root_dir:?}"
-        ))? {
-            build_info.projects.push(Project {
+    for pattern in build_command.only.iter() {
+        if !build_configs
+            .iter()
+            .any(|(_, x)| pattern.matches(&x.project_name))
+        {
+            warn!("--only {pattern} doesn't match any project");
+        }
+    }
+
+    // Indices into build_configs
+    let selected: Vec<usize> = (0..build_configs.len())
+        .filter(|&i| build_command.selects(&build_configs[i].1.project_name))
+        .collect();
+
+    if build_command.dry_run {
+        return plan::print_plan(
+            &ctx,
+            &build_configs,
+            &selected,
+            build_command.show_dockerfile,
+        );
+    }
+
+    fs::create_dir_all(&build_command.out_dir).context("Couldn't create bob_build dir")?;
+
+    interrupt::install_handler().context("Couldn't install Ctrl-C handler")?;
+
+    let jobs = build_command.jobs.get().min(selected.len());
+    info!(
+        "Building {} of {} projects, {jobs} at a time",
+        selected.len(),
+        build_configs.len()
+    );
+
+    // Indexed like build_configs, so buildinfo.toml keeps the order of the config tree no matter
+    // which build finishes first. Starts out with the previous builds, so projects that fail keep
+    // their last good entry
+    let built_projects: Mutex<Vec<Option<Project>>> = Mutex::new(
+        build_configs
+            .iter()
+            .enumerate()
+            .map(|(i, (_, build_config))| {
+                if selected.contains(&i) {
+                    prev_project_info(&ctx, build_config).cloned()
+                } else {
+                    // Unselected projects are carried over untouched
+                    build_info_prev.as_ref().and_then(|x| {
+                        x.projects
+                            .iter()
+                            .find(|x| x.name == build_config.project_name)
+                            .cloned()
+                    })
+                }
+            })
+            .collect(),
+    );
+    let reports: Mutex<Vec<ProjectReport>> = Mutex::new(
+        build_configs
+            .iter()
+            .enumerate()
+            .map(|(i, (_, build_config))| ProjectReport {
                 name: build_config.project_name.clone(),
-                hash: bin_build_result.dir_hash,
-                build_date: chrono::Local::now().into(),
+                outcome: if selected.contains(&i) {
+                    Outcome::Cancelled
+                } else {
+                    Outcome::Unselected
+                },
+                duration: Duration::ZERO,
+                failure_log: None,
+            })
+            .collect(),
+    );
+    let next_job = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| {
+                // Unless we keep going, don't start new builds once one has failed, but let
+                // running ones finish. Nothing new is started after Ctrl-C either
+                while (build_command.keep_going || !failed.load(Ordering::SeqCst))
+                    && !interrupt::is_interrupted()
+                {
+                    let Some(&i) = selected.get(next_job.fetch_add(1, Ordering::SeqCst)) else {
+                        break;
+                    };
+                    let (bob_toml_path, build_config) = &build_configs[i];
+
+                    let start = Instant::now();
+                    let output = ProjectOutput::with_log(
+                        &build_config.project_name,
+                        ctx.out_dir
+                            .join(log_path_relative(&build_config.project_name)),
+                    );
+                    let result = build_project(&ctx, bob_toml_path, build_config, &output)
+                        .and_then(|(project, outcome)| {
+                            let mut built_projects = built_projects.lock().unwrap();
+                            built_projects[i] = Some(project);
+                            write_build_info(
+                                ctx.out_dir,
+                                &BuildInfo {
+                                    projects: built_projects.iter().flatten().cloned().collect(),
+                                },
+                            )?;
+                            Ok(outcome)
+                        });
+
+                    let outcome = result.unwrap_or_else(|e| {
+                        if e.downcast_ref::<interrupt::Interrupted>().is_some() {
+                            return Outcome::Cancelled;
+                        }
+                        failed.store(true, Ordering::SeqCst);
+                        error!("[{}] Build failed: {e:#}", build_config.project_name);
+                        Outcome::Failed(e)
+                    });
+                    let report = &mut reports.lock().unwrap()[i];
+                    if matches!(outcome, Outcome::Failed(_)) && build_command.log_tail > 0 {
+                        report.failure_log = output.log_tail(build_command.log_tail);
+                    }
+                    report.outcome = outcome;
+                    report.duration = start.elapsed();
+                }
             });
+        }
+    });
+
+    // Only removed if empty, a failed build may still be in it
+    let _ = fs::remove_dir(build_command.out_dir.join(STAGING_PATH_RELATIVE));
+    let _ = fs::remove_dir(build_command.out_dir.join(OLD_PATH_RELATIVE));
+
+    let reports = reports.into_inner().unwrap();
+    print_summary(&reports);
+
+    let failed_count = reports
+        .iter()
+        .filter(|x| matches!(x.outcome, Outcome::Failed(_)))
+        .count();
+    if failed_count > 0 {
+        return Err(anyhow!("{failed_count} project(s) failed to build"));
+    }
+    interrupt::check()?;
+
+    let build_info = BuildInfo {
+        projects: built_projects
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect(),
+    };
+
+    info!("Copy of buildinfo.toml:\n{}", build_info.to_string().trim());
+    info!("Done!");
+
+    Ok(())
+}
+
+struct BuildContext<'a> {
+    /// Canonical path of the root bob.toml
+    config_path: &'a Path,
+    out_dir: &'a Path,
+    build_info_prev: Option<&'a BuildInfo>,
+    runtime: &'a dyn ContainerRuntime,
+    max_output_size: u64,
+    max_output_entries: u64,
+    /// Rebuild even if the cache key matches
+    force: bool,
+    prune_images: bool,
+    /// Limits from the command line, for projects that don't set them
+    default_limits: ResourceLimits,
+}
+
+pub fn write_build_info(out_dir: &Path, build_info: &BuildInfo) -> anyhow::Result<()> {
+    fs::File::create(out_dir.join(BUILDINFO_PATH_RELATIVE))
+        .context("Couldn't create buildinfo.toml")?
+        .write_all(build_info.to_string().as_bytes())
+        .context("Couldn't write buildinfo.toml")
+}
+
+fn prev_project_info<'a>(ctx: &BuildContext<'a>, build_config: &BobConfig) -> Option<&'a Project> {
+    // Previous builds only count if their output is still there
+    if !ctx.out_dir.join(&build_config.project_name).exists() {
+        return None;
+    }
+    ctx.build_info_prev?
+        .projects
+        .iter()
+        .find(|x| x.name == build_config.project_name)
+}
+
+/// Moves `staging_dir` to `dst_dir`, replacing whatever was there before. `old_dir` is where the
+/// previous `dst_dir` is kept until the new one is in place, it has to be unique per project
+fn replace_dir(staging_dir: &Path, dst_dir: &Path, old_dir: &Path) -> anyhow::Result<()> {
+    if !dst_dir.exists() {
+        return fs::rename(staging_dir, dst_dir)
+            .context(format!("moving {staging_dir:?} to {dst_dir:?}"));
+    }
+
+    // Directories can't be renamed over each other on all platforms, so the old one is moved out
+    // of the way first
+    if let Err(e) = fs::remove_dir_all(old_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e).context(format!("Couldn't clear {old_dir:?}"));
+        }
+    }
+    if let Some(parent) = old_dir.parent() {
+        fs::create_dir_all(parent).context(format!("Couldn't create {parent:?}"))?;
+    }
+    fs::rename(dst_dir, old_dir).context(format!("moving {dst_dir:?} to {old_dir:?}"))?;
+    if let Err(e) = fs::rename(staging_dir, dst_dir) {
+        // Put the old output back, it's better than nothing
+        let _ = fs::rename(old_dir, dst_dir);
+        return Err(e).context(format!("moving {staging_dir:?} to {dst_dir:?}"));
+    }
+    fs::remove_dir_all(old_dir).context(format!("Couldn't remove {old_dir:?}"))
+}
+
+/// Log of a project's last build, relative to the output dir
+pub fn log_path_relative(project_name: &str) -> PathBuf {
+    Path::new(LOGS_PATH_RELATIVE).join(format!("{project_name}.log"))
+}
+
+/// A project's source root is the directory of the bob.toml defining it
+pub fn project_src_root(bob_toml_path: &Path) -> anyhow::Result<PathBuf> {
+    bob_toml_path
+        .canonicalize()
+        .context("bob config parent dir doesn't exist")?
+        .parent()
+        .ok_or(anyhow!("couldn't get parent dir of bob config"))?
+        .canonicalize()
+        .map_err(Into::into)
+}
+
+/// Builds a single project, the returned outcome is either [`Outcome::Built`] or
+/// [`Outcome::Skipped`]
+fn build_project(
+    ctx: &BuildContext,
+    bob_toml_path: &Path,
+    build_config: &BobConfig,
+    output: &ProjectOutput,
+) -> anyhow::Result<(Project, Outcome)> {
+    let proj_src_root_dir = project_src_root(bob_toml_path).context(BuildStep::Prepare)?;
 
-            if let Err(e) = fs::remove_dir_all(&proj_build_root_dir) {
-                if e.kind() != std::io::ErrorKind::NotFound {
-                    return Err(e).context("Couldn't clear old project dir in bob_build");
+    let proj_build_root_dir = ctx.out_dir.join(&build_config.project_name);
+    let proj_staging_dir = ctx
+        .out_dir
+        .join(STAGING_PATH_RELATIVE)
+        .join(&build_config.project_name);
+    let proj_old_dir = ctx
+        .out_dir
+        .join(OLD_PATH_RELATIVE)
+        .join(&build_config.project_name);
+
+    let prev_project_info = prev_project_info(ctx, build_config);
+
+    // On failure the previous output is left untouched, and the half done build is useless
+    let discard_staging_dir = |_: &anyhow::Error| {
+        let _ = fs::remove_dir_all(&proj_staging_dir);
+    };
+
+    let mut binary_paths = BTreeMap::new();
+    let Some(bin_build_result) = bin_builder::build(
+        proj_src_root_dir.to_owned(),
+        build_config,
+        prev_project_info.and_then(|x| x.cache_key.as_ref()),
+        &BuildOptions {
+            runtime: ctx.runtime,
+            config_path: ctx.config_path,
+            output,
+            force: ctx.force,
+            prune_images: ctx.prune_images,
+            default_limits: ctx.default_limits,
+            max_output_size: ctx.max_output_size,
+        },
+        &mut |tar_stream| {
+            // Might be left over from an interrupted build
+            if let Err(e) = fs::remove_dir_all(&proj_staging_dir) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e).context("Couldn't clear old staging dir in bob_build");
                 }
             };
-            fs::create_dir_all(&proj_build_root_dir)
-                .context("Couldn't create project dir for bob_build")?;
-
-            info!("Decompressing built binaries...");
+            fs::create_dir_all(&proj_staging_dir)
+                .context("Couldn't create staging dir for bob_build")?;
 
-            let (windows_binary_path, linux_binary_path) =
-                build_bot_bins(bin_build_result.tar_binary, &proj_build_root_dir)?;
+            info!(
+                "[{}] Decompressing built binaries...",
+                build_config.project_name
+            );
 
-            build_bot_tomls(
-                &build_config
-                    .bot_configs
-                    .iter()
-                    .map(|x| proj_src_root_dir.join(x))
-                    .collect::<Vec<_>>(),
-                // &build_config.project_name,
-                &proj_src_root_dir,
-                &proj_build_root_dir,
-                windows_binary_path,
-                linux_binary_path,
+            binary_paths = build_bot_bins(
+                tar_stream,
+                &proj_staging_dir,
+                ctx.max_output_entries,
+                ctx.max_output_size,
             )
             .context(format!(
-                "Couldn't build bot tomls for project {}",
-                &build_config.project_name
+                "Invalid tar output of project {}",
+                build_config.project_name
             ))?;
-        } else {
-            let prev_project_info = prev_project_info.unwrap();
-            // Old is good
-            build_info.projects.push(Project {
-                name: build_config.project_name.clone(),
-                hash: prev_project_info.hash,
-                build_date: prev_project_info.build_date,
-            });
-        };
+            Ok(())
+        },
+    )
+    .context(format!(
+        "Failed to build binaries for project with root at {proj_src_root_dir:?}"
+    ))
+    .inspect_err(discard_staging_dir)?
+    else {
+        // Old is good
+        return Ok((prev_project_info.unwrap().clone(), Outcome::Skipped));
+    };
 
-        fs::File::create(build_command.out_dir.join("buildinfo.toml"))?
-            .write_all(build_info.to_string().as_bytes())?;
+    if let Some(entry) = &build_config.entry {
+        for (os, pattern) in [(Os::Windows, &entry.windows), (Os::Linux, &entry.linux)] {
+            let Some(pattern) = pattern else {
+                continue;
+            };
+            let entries = resolve_entry(&proj_staging_dir, os, pattern)
+                .context(format!(
+                    "Couldn't resolve {os} entry of project {}",
+                    build_config.project_name
+                ))
+                .context(BuildStep::BotTomls)
+                .inspect_err(discard_staging_dir)?;
+            // A declared entry replaces whatever was detected for its OS
+            binary_paths.retain(|platform, _| platform.os != os);
+            binary_paths.extend(entries);
+        }
     }
 
-    info!("Copy of buildinfo.toml:\n{}", build_info.to_string().trim());
-    info!("Done!");
+    build_bot_tomls(
+        &build_config
+            .bot_configs
+            .iter()
+            .map(|x| proj_src_root_dir.join(x))
+            .collect::<Vec<_>>(),
+        // &build_config.project_name,
+        &proj_src_root_dir,
+        &proj_staging_dir,
+        &binary_paths,
+        build_config.platforms.as_deref(),
+        build_config.per_arch_run_commands,
+    )
+    .context(format!(
+        "Couldn't build bot tomls for project {}",
+        &build_config.project_name
+    ))
+    .context(BuildStep::BotTomls)
+    .and_then(|_| {
+        replace_dir(&proj_staging_dir, &proj_build_root_dir, &proj_old_dir)
+            .context(BuildStep::Install)
+    })
+    .inspect_err(discard_staging_dir)?;
+
+    let project = Project {
+        name: build_config.project_name.clone(),
+        hash: bin_build_result.cache_key.combined(),
+        build_date: chrono::Local::now().into(),
+        platforms: build_config.platforms.clone(),
+        cache_key: Some(bin_build_result.cache_key),
+        log_file: Some(log_path_relative(&build_config.project_name))
+            .filter(|x| ctx.out_dir.join(x).is_file()),
+    };
+    Ok((project, Outcome::Built))
+}
+
+/// The platform a built file is for, going by the target triple of its `[PLATFORM]` dir.
+/// Files at the root of the project dir are assumed to be x86_64, ones in a dir that isn't a
+/// target triple aren't for any platform
+fn built_file_platform(relative_path: &Path, os: Os) -> Option<Platform> {
+    let arch = if relative_path.components().count() <= 1 {
+        Arch::X86_64
+    } else {
+        TargetTriple::of_built_file(relative_path)?.arch
+    };
+    Some(Platform { arch, os })
+}
+
+/// Finds the built files matching an `entry` glob from bob.toml, exactly one per architecture
+fn resolve_entry(
+    proj_build_root_dir: &Path,
+    os: Os,
+    pattern: &str,
+) -> anyhow::Result<BTreeMap<Platform, PathBuf>> {
+    if Path::new(pattern)
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(anyhow!(
+            "entry {pattern:?} must be relative to the project dir, without `..`"
+        ));
+    }
+
+    let full_pattern = format!(
+        "{}/{pattern}",
+        glob::Pattern::escape(
+            proj_build_root_dir
+                .to_str()
+                .context("bob_build path isn't valid UTF-8")?
+        )
+    );
+    let matches = glob::glob(&full_pattern)
+        .context(format!("invalid entry glob {pattern:?}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .context("globbing entry failed")?
+        .into_iter()
+        .filter(|x| x.is_file())
+        .collect::<Vec<_>>();
+
+    if matches.is_empty() {
+        return Err(anyhow!("entry {pattern:?} doesn't match any built file"));
+    }
+
+    let mut entries = BTreeMap::<Platform, Vec<PathBuf>>::new();
+    for path in matches {
+        let relative_path = path.strip_prefix(proj_build_root_dir).unwrap_or(&path);
+        let platform = built_file_platform(relative_path, os).context(format!(
+            "entry {pattern:?} matches {relative_path:?}, which isn't in a target triple dir"
+        ))?;
+        entries.entry(platform).or_default().push(path);
+    }
+
+    entries
+        .into_iter()
+        .map(|(platform, paths)| match paths.as_slice() {
+            [path] => Ok((platform, path.clone())),
+            _ => Err(anyhow!(
+                "entry {pattern:?} must match exactly one built file per platform, but matches {} for {platform}: {:?}",
+                paths.len(),
+                paths
+                    .iter()
+                    .map(|x| x.strip_prefix(proj_build_root_dir).unwrap_or(x))
+                    .collect::<Vec<_>>()
+            )),
+        })
+        .collect()
+}
+
+/// Turns a path from the built tar into one relative to the project build dir, rejecting
+/// anything that could point outside of it
+fn tar_entry_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut relative_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(x) => relative_path.push(x),
+            Component::CurDir => {}
+            Component::ParentDir => return Err(anyhow!("path {path:?} contains `..`")),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!("path {path:?} is absolute"))
+            }
+        }
+    }
+    if relative_path.as_os_str().is_empty() {
+        return Err(anyhow!("path {path:?} is empty"));
+    }
+    Ok(relative_path)
+}
+
+/// Makes sure nothing is written through a symlink extracted earlier
+fn check_no_symlink_ancestors(
+    proj_build_root_dir: &Path,
+    relative_path: &Path,
+) -> anyhow::Result<()> {
+    for ancestor in relative_path.ancestors().skip(1) {
+        if ancestor.as_os_str().is_empty() {
+            break;
+        }
+        if fs::symlink_metadata(proj_build_root_dir.join(ancestor)).is_ok_and(|x| x.is_symlink()) {
+            return Err(anyhow!(
+                "path {relative_path:?} goes through symlink {ancestor:?}"
+            ));
+        }
+    }
+    Ok(())
+}
 
+/// Symlinks must be relative and stay inside the project build dir. `..` is only allowed at the
+/// start, as going up after following another symlink could still end up outside of it
+fn check_symlink_target(relative_path: &Path, target: &Path) -> anyhow::Result<()> {
+    let mut depth = relative_path.components().count() - 1;
+    let mut went_down = false;
+    for component in target.components() {
+        match component {
+            Component::ParentDir if !went_down && depth > 0 => depth -= 1,
+            Component::ParentDir => {
+                return Err(anyhow!(
+                    "symlink {relative_path:?} points outside of the project: {target:?}"
+                ))
+            }
+            Component::Normal(_) => went_down = true,
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!(
+                    "symlink {relative_path:?} has an absolute target: {target:?}"
+                ))
+            }
+        }
+    }
     Ok(())
 }
 
 fn build_bot_bins(
-    bin: Vec<u8>,
+    tar_stream: &mut dyn Read,
     proj_build_root_dir: &Path,
-) -> anyhow::Result<(Option<PathBuf>, Option<PathBuf>)> {
-    let mut windows_binary_path = None;
-    let mut linux_binary_path = None;
+    max_entries: u64,
+    max_size: u64,
+) -> anyhow::Result<BTreeMap<Platform, PathBuf>> {
+    let mut binary_paths = BTreeMap::new();
+    let mut entry_count = 0;
+    let mut total_size = 0u64;
 
-    let mut archive = tar::Archive::new(Cursor::new(bin));
+    let mut archive = tar::Archive::new(tar_stream);
     for entry in archive
         .entries()
         .context("Couldn't build entries in built tar file")?
     {
-        let entry = entry.context("Couldn't read entry in built tar file")?;
-        if entry.header().entry_type().is_dir() {
+        let mut entry = entry.context("Couldn't read entry in built tar file")?;
+
+        entry_count += 1;
+        if entry_count > max_entries {
+            return Err(anyhow!(
+                "more than {max_entries} entries, raise --max-output-entries if that's intended"
+            ));
+        }
+
+        let entry_type = entry.header().entry_type();
+        if entry_type.is_pax_global_extensions() {
             continue;
         }
-        let entry_path = entry
+
+        let raw_entry_path = entry
             .path()
             .context("Couldn't read path of entry in built tar file")?
             .into_owned();
+        // Tars of a whole dir start with the dir itself, which already exists
+        if entry_type.is_dir() && raw_entry_path.components().all(|x| x == Component::CurDir) {
+            continue;
+        }
+        let entry_path = tar_entry_path(&raw_entry_path)?;
+        check_no_symlink_ancestors(proj_build_root_dir, &entry_path)?;
         let path_in_build = proj_build_root_dir.join(&entry_path);
+
+        if entry_type.is_dir() {
+            fs::create_dir_all(&path_in_build).context("Couldn't create dir in bob_build")?;
+            continue;
+        }
+
         fs::create_dir_all(path_in_build.parent().unwrap())
             .context("Couldn't create dir in bob_build")?;
-        let entry_mode = entry.header().mode().unwrap_or_default();
-        let bytes = entry.bytes().map(|x| x.unwrap()).collect::<Vec<u8>>();
 
-        // TODO: A decently big flaw here is that we cannot use shell files as the
-        //       entry point for a bot. Maybe we can add something to bob.toml?
-        match (
-            infer::get(&bytes).map(|x| x.mime_type()),
+        if entry_type.is_symlink() {
+            let target = entry
+                .link_name()
+                .context("Couldn't read symlink target in built tar file")?
+                .context(format!("symlink {entry_path:?} has no target"))?
+                .into_owned();
+            check_symlink_target(&entry_path, &target)?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&target, &path_in_build)
+                .context(format!("Couldn't create symlink {entry_path:?}"))?;
+            #[cfg(windows)]
+            std::os::windows::fs::symlink_file(&target, &path_in_build)
+                .context(format!("Couldn't create symlink {entry_path:?}"))?;
+            continue;
+        }
+
+        if entry_type.is_hard_link() {
+            // Hardlink targets are relative to the root of the tar, not to the link
+            let target = tar_entry_path(
+                &entry
+                    .link_name()
+                    .context("Couldn't read hardlink target in built tar file")?
+                    .context(format!("hardlink {entry_path:?} has no target"))?,
+            )?;
+            check_no_symlink_ancestors(proj_build_root_dir, &target)?;
+            let target_in_build = proj_build_root_dir.join(&target);
+            if !fs::symlink_metadata(&target_in_build).is_ok_and(|x| x.is_file()) {
+                return Err(anyhow!(
+                    "hardlink {entry_path:?} doesn't point at a file extracted before it: {target:?}"
+                ));
+            }
+            fs::hard_link(&target_in_build, &path_in_build)
+                .context(format!("Couldn't create hardlink {entry_path:?}"))?;
+            continue;
+        }
+
+        if !(entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse()) {
+            return Err(anyhow!(
+                "entry {entry_path:?} has unsupported type {entry_type:?}, only files, dirs and links are allowed"
+            ));
+        }
+
+        // Sparse files can be a lot bigger than what they take up in the tar
+        total_size = total_size.saturating_add(entry.size());
+        if total_size > max_size {
+            return Err(anyhow!(
+                "extracted files are bigger than {max_size} bytes, raise --max-output-size if that's intended"
+            ));
+        }
+
+        // No setuid and the like
+        let entry_mode = entry.header().mode().unwrap_or_default() & 0o777;
+        // Only the start of the file is needed to detect its type, the rest is streamed to disk
+        let mut head = Vec::with_capacity(FILE_TYPE_DETECTION_BYTES as usize);
+        (&mut entry)
+            .take(FILE_TYPE_DETECTION_BYTES)
+            .read_to_end(&mut head)
+            .context("Couldn't read entry in built tar file")?;
+
+        // Only finds real executables, projects with shell script entry points or multiple
+        // executables should set `entry` in their bob.toml
+        let os = match (
+            infer::get(&head).map(|x| x.mime_type()),
             entry_path
                 .file_name()
                 .and_then(|x| x.to_str())
@@ -158,25 +705,40 @@ fn build_bot_bins(
             (Some("application/vnd.microsoft.portable-executable"), file_name)
                 if file_name.ends_with(".exe") =>
             {
-                windows_binary_path = Some(path_in_build.clone())
+                Some(Os::Windows)
             }
             (Some("application/x-executable"), file_name)
                 if !file_name.starts_with("lib") && !file_name.ends_with("so") =>
             {
-                linux_binary_path = Some(path_in_build.clone())
+                Some(Os::Linux)
             }
-            _ => {}
+            _ => None,
+        };
+        match os.map(|os| (os, built_file_platform(&entry_path, os))) {
+            Some((os, Some(platform))) => {
+                if let Some(prev) = binary_paths.insert(platform, path_in_build.clone()) {
+                    warn!("Found multiple {platform} binaries, using {path_in_build:?} over {prev:?}, set `entry.{os}` in bob.toml to pick one");
+                }
+            }
+            Some((_, None)) => warn!(
+                "{entry_path:?} isn't in a target triple dir, it won't be used for any platform"
+            ),
+            None => {}
         }
 
-        let mut created_file = fs::File::create_new(path_in_build)?;
+        let mut created_file = fs::File::create_new(&path_in_build).context(format!(
+            "Couldn't create {entry_path:?}, is it in the tar twice?"
+        ))?;
 
         #[cfg(unix)]
         created_file.set_permissions(fs::Permissions::from_mode(entry_mode))?;
 
-        created_file.write_all(&bytes)?;
+        created_file.write_all(&head)?;
+        io::copy(&mut entry, &mut created_file)
+            .context("Couldn't write entry of built tar file to bob_build")?;
     }
 
-    Ok((windows_binary_path, linux_binary_path))
+    Ok(binary_paths)
 }
 
 fn build_bot_tomls(
@@ -184,9 +746,49 @@ fn build_bot_tomls(
     // proj_name: &str,
     proj_src_root_dir: &Path,
     proj_build_root_dir: &Path,
-    windows_binary_path: Option<PathBuf>,
-    linux_binary_path: Option<PathBuf>,
+    binary_paths: &BTreeMap<Platform, PathBuf>,
+    platforms: Option<&[Os]>,
+    per_arch_run_commands: bool,
 ) -> anyhow::Result<()> {
+    // Binaries of platforms the project didn't declare are left out of the bot tomls
+    let binary_paths = binary_paths
+        .iter()
+        .filter(|(platform, _)| platform.os.is_allowed(platforms))
+        .collect::<BTreeMap<_, _>>();
+
+    // The plain run commands point at the x86_64 binary, or whatever arch there is otherwise
+    let mut run_commands = BTreeMap::new();
+    for os in [Os::Windows, Os::Linux] {
+        let os_binary_path = binary_paths
+            .get(&Platform {
+                arch: Arch::X86_64,
+                os,
+            })
+            .or_else(|| {
+                binary_paths
+                    .iter()
+                    .find(|(platform, _)| platform.os == os)
+                    .map(|(_, path)| path)
+            });
+        match os_binary_path {
+            Some(path) => {
+                run_commands.insert(os.run_command_key().to_owned(), (os, *path));
+            }
+            None if os.is_required(platforms) => {
+                return Err(anyhow!("No {os} binary found for this project"));
+            }
+            None => {}
+        }
+    }
+    // With more than one arch of an OS, the plain run command only fits one of them, so
+    // `bob split` needs the per-arch ones to pick the right binary
+    let is_multi_arch = |os: Os| binary_paths.keys().filter(|x| x.os == os).count() > 1;
+    for (platform, path) in &binary_paths {
+        if per_arch_run_commands || is_multi_arch(platform.os) {
+            run_commands.insert(platform.run_command_key(), (platform.os, *path));
+        }
+    }
+
     for bot_toml_path in bot_configs {
         if !bot_toml_path.exists() {
             return Err(anyhow!("bot_toml_path {:?} doesn't exist", bot_toml_path));
@@ -214,40 +816,25 @@ fn build_bot_tomls(
                 bot_toml_path
             ))?;
 
-        if let Some(windows_binary_path) = &windows_binary_path {
-            settings_table.insert(
-                "run_command".to_owned(),
-                toml::Value::String(
-                    windows_binary_path
-                        .canonicalize()?
-                        .strip_prefix(proj_build_root_dir.canonicalize()?)?
-                        .as_os_str()
-                        .to_str()
-                        .unwrap()
-                        .to_owned()
-                        .replace("/", "\\\\"),
-                    //  ^ thank you microsoft, great os
-                ),
-            );
-        } else {
-            return Err(anyhow!("No windows binary found for this project"));
-        }
-
-        if let Some(linux_binary_path) = &linux_binary_path {
-            settings_table.insert(
-                "run_command_linux".to_owned(),
-                toml::Value::String(
-                    linux_binary_path
-                        .canonicalize()?
-                        .strip_prefix(proj_build_root_dir.canonicalize()?)?
-                        .as_os_str()
-                        .to_str()
-                        .unwrap()
-                        .to_owned(),
-                ),
-            );
-        } else {
-            settings_table.remove("run_command_linux");
+        // Stale per-arch run commands would point at binaries that aren't there
+        settings_table.retain(|key, _| {
+            !(key.starts_with(Os::Windows.run_command_key())
+                || key.starts_with(Os::Linux.run_command_key()))
+        });
+        for (key, (os, binary_path)) in &run_commands {
+            let run_command = binary_path
+                .canonicalize()?
+                .strip_prefix(proj_build_root_dir.canonicalize()?)?
+                .as_os_str()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            let run_command = match os {
+                Os::Windows => run_command.replace("/", "\\\\"),
+                //  ^ thank you microsoft, great os
+                Os::Linux => run_command,
+            };
+            settings_table.insert(key.clone(), toml::Value::String(run_command));
         }
 
         let bot_toml_parent = bot_toml_path.parent().unwrap();
@@ -282,3 +869,276 @@ fn build_bot_tomls(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bin_builder::uid::uid;
+    use interrupt::TempPath;
+
+    fn temp_dir() -> TempPath {
+        let dir = TempPath::new(std::env::temp_dir().join(format!("bob-test-{}", uid())));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A tar with `entries` of (path, type, link target or file contents). Paths are written
+    /// as is, so they can be malicious
+    fn tar_of(entries: &[(&str, tar::EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for &(path, entry_type, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_entry_type(entry_type);
+            header.set_mode(0o755);
+            let data = if entry_type.is_symlink() || entry_type.is_hard_link() {
+                header.set_link_name(data).unwrap();
+                ""
+            } else {
+                data
+            };
+            header.set_size(data.len() as u64);
+            header.set_cksum();
+            builder.append(&header, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn extract(tar: &[u8], dir: &Path) -> anyhow::Result<BTreeMap<Platform, PathBuf>> {
+        build_bot_bins(&mut &tar[..], dir, 100, 1 << 20)
+    }
+
+    /// Writes a config tree with a single custom project `p` to `dir`
+    fn write_config_tree(dir: &Path) {
+        fs::create_dir(dir.join("p")).unwrap();
+        fs::write(dir.join("bob.toml"), "dependencies = [\"p/bob.toml\"]\n").unwrap();
+        fs::write(
+            dir.join("p/bob.toml"),
+            r#"
+                [[config]]
+                project_name = "p"
+                bot_configs = ["bot.toml"]
+                [config.builder_config]
+                builder_type = "custom"
+                dockerfile = "Dockerfile"
+            "#,
+        )
+        .unwrap();
+        fs::write(dir.join("p/Dockerfile"), "FROM scratch\n").unwrap();
+        fs::write(
+            dir.join("p/bot.toml"),
+            "[settings]\nname = \"Bot\"\nrun_command = \"\"\n",
+        )
+        .unwrap();
+    }
+
+    /// Runs `bob build` on the config tree in `dir` with the fake runtime
+    fn build_with_fake_runtime(dir: &Path, out_dir: &Path) {
+        let build_command = <BuildCommand as clap::Parser>::try_parse_from([
+            "build".as_ref(),
+            dir.join("bob.toml").as_os_str(),
+            "--out-dir".as_ref(),
+            out_dir.as_os_str(),
+            "--runtime".as_ref(),
+            "fake".as_ref(),
+        ])
+        .unwrap();
+        command_build(build_command).unwrap();
+    }
+
+    #[test]
+    fn command_build_with_fake_runtime() {
+        let dir = temp_dir();
+        write_config_tree(&dir);
+        let out_dir = dir.join("out");
+        build_with_fake_runtime(&dir, &out_dir);
+
+        // The binaries of the fake runtime's canned tar
+        assert!(out_dir.join("p/x86_64-unknown-linux-gnu/bot").is_file());
+        assert!(out_dir.join("p/x86_64-pc-windows-msvc/bot.exe").is_file());
+        assert!(!out_dir.join(STAGING_PATH_RELATIVE).exists());
+        assert!(!out_dir.join(OLD_PATH_RELATIVE).exists());
+
+        let bot_toml: toml::Table = fs::read_to_string(out_dir.join("p/bot.toml"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let settings = bot_toml["settings"].as_table().unwrap();
+        // Windows paths are written with escaped backslashes
+        assert_eq!(settings["name"].as_str(), Some("Bot"));
+        assert_eq!(
+            settings["run_command"].as_str(),
+            Some("x86_64-pc-windows-msvc\\\\bot.exe")
+        );
+        assert_eq!(
+            settings["run_command_linux"].as_str(),
+            Some("x86_64-unknown-linux-gnu/bot")
+        );
+
+        let build_info = BuildInfo::from_str(
+            &fs::read_to_string(out_dir.join(BUILDINFO_PATH_RELATIVE)).unwrap(),
+        )
+        .unwrap();
+        let [project] = build_info.projects.as_slice() else {
+            panic!("expected one project in {build_info:?}");
+        };
+        assert_eq!(project.name, "p");
+        assert_eq!(
+            Some(project.hash),
+            project.cache_key.as_ref().map(|x| x.combined())
+        );
+        assert_eq!(project.log_file, Some(log_path_relative("p")));
+        assert!(out_dir.join(log_path_relative("p")).is_file());
+    }
+
+    #[test]
+    fn command_build_reuses_unchanged_builds() {
+        let dir = temp_dir();
+        write_config_tree(&dir);
+        let out_dir = dir.join("out");
+        let read_build_info = || {
+            BuildInfo::from_str(&fs::read_to_string(out_dir.join(BUILDINFO_PATH_RELATIVE)).unwrap())
+                .unwrap()
+        };
+
+        build_with_fake_runtime(&dir, &out_dir);
+        let first = read_build_info();
+        build_with_fake_runtime(&dir, &out_dir);
+        let second = read_build_info();
+        assert_eq!(first.projects[0].build_date, second.projects[0].build_date);
+
+        fs::write(dir.join("p/Dockerfile"), "FROM scratch\nCOPY . .\n").unwrap();
+        build_with_fake_runtime(&dir, &out_dir);
+        let third = read_build_info();
+        assert_ne!(second.projects[0].hash, third.projects[0].hash);
+        assert!(out_dir.join("p/x86_64-unknown-linux-gnu/bot").is_file());
+    }
+
+    #[test]
+    fn built_file_platform_only_assumes_x86_64_at_the_root() {
+        let arch = |path: &str| built_file_platform(Path::new(path), Os::Linux).map(|x| x.arch);
+        assert_eq!(arch("bot"), Some(Arch::X86_64));
+        assert_eq!(arch("x86_64-unknown-linux-gnu/bot"), Some(Arch::X86_64));
+        assert_eq!(
+            arch("aarch64-unknown-linux-gnu/bin/bot"),
+            Some(Arch::Aarch64)
+        );
+        assert_eq!(arch("armv7-unknown-linux-gnueabihf/bot"), None);
+        assert_eq!(arch("bin/bot"), None);
+    }
+
+    #[test]
+    fn tar_entry_path_normalizes_relative_paths() {
+        assert_eq!(tar_entry_path(Path::new("a/b")).unwrap(), Path::new("a/b"));
+        assert_eq!(
+            tar_entry_path(Path::new("./a/./b")).unwrap(),
+            Path::new("a/b")
+        );
+    }
+
+    #[test]
+    fn tar_entry_path_rejects_traversal_and_absolute_paths() {
+        for path in ["../x", "a/../../x", "a/..", "/etc/passwd", "", "./"] {
+            assert!(tar_entry_path(Path::new(path)).is_err(), "{path:?}");
+        }
+    }
+
+    #[test]
+    fn check_symlink_target_allows_relative_targets_inside() {
+        for (link, target) in [
+            ("link", "target"),
+            ("a/link", "../b"),
+            ("a/b/link", "../../c/d"),
+            ("a/link", "./b"),
+        ] {
+            assert!(
+                check_symlink_target(Path::new(link), Path::new(target)).is_ok(),
+                "{link:?} -> {target:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_symlink_target_rejects_escapes() {
+        for (link, target) in [
+            ("link", "../x"),
+            ("a/link", "../../x"),
+            // Going up after going down could follow another symlink
+            ("a/link", "b/../../x"),
+            ("a/link", "b/.."),
+            ("link", "/etc/passwd"),
+        ] {
+            assert!(
+                check_symlink_target(Path::new(link), Path::new(target)).is_err(),
+                "{link:?} -> {target:?}"
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_no_symlink_ancestors_rejects_writing_through_symlinks() {
+        let dir = temp_dir();
+        fs::create_dir(dir.join("sub")).unwrap();
+        std::os::unix::fs::symlink("sub", dir.join("link")).unwrap();
+
+        assert!(check_no_symlink_ancestors(&dir, Path::new("sub/x")).is_ok());
+        // Replacing the link itself is caught by create_new
+        assert!(check_no_symlink_ancestors(&dir, Path::new("link")).is_ok());
+        assert!(check_no_symlink_ancestors(&dir, Path::new("link/x")).is_err());
+        assert!(check_no_symlink_ancestors(&dir, Path::new("link/a/x")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_bot_bins_extracts_files_and_relative_symlinks() {
+        let dir = temp_dir();
+        let tar = tar_of(&[
+            ("./", tar::EntryType::Directory, ""),
+            ("./sub/", tar::EntryType::Directory, ""),
+            ("./sub/run.sh", tar::EntryType::Regular, "#!/bin/sh\n"),
+            ("./run.sh", tar::EntryType::Symlink, "sub/run.sh"),
+            ("./sub/link", tar::EntryType::Symlink, "../run.sh"),
+        ]);
+        extract(&tar, &dir).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.join("sub/run.sh")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        assert_eq!(
+            fs::read_link(dir.join("run.sh")).unwrap(),
+            Path::new("sub/run.sh")
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("sub/link")).unwrap(),
+            "#!/bin/sh\n"
+        );
+    }
+
+    #[test]
+    fn build_bot_bins_rejects_malicious_entries() {
+        let cases: &[&[(&str, tar::EntryType, &str)]] = &[
+            &[("../evil", tar::EntryType::Regular, "x")],
+            &[("/tmp/evil", tar::EntryType::Regular, "x")],
+            &[("evil", tar::EntryType::Symlink, "../../evil")],
+            &[("evil", tar::EntryType::Symlink, "/etc/passwd")],
+            &[
+                ("link", tar::EntryType::Symlink, "."),
+                ("sub/link2", tar::EntryType::Symlink, "../link"),
+                ("sub/link2/evil", tar::EntryType::Regular, "x"),
+            ],
+            &[("dev", tar::EntryType::Char, "")],
+            &[("dev", tar::EntryType::Block, "")],
+            &[("fifo", tar::EntryType::Fifo, "")],
+            &[("hard", tar::EntryType::Link, "../evil")],
+        ];
+        for entries in cases {
+            let parent = temp_dir();
+            let dir = parent.join("build");
+            fs::create_dir(&dir).unwrap();
+            assert!(extract(&tar_of(entries), &dir).is_err(), "{entries:?}");
+            assert!(!parent.join("evil").exists(), "{entries:?}");
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    fs,
+    io::{self, BufRead as _, BufReader, Read, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::{SecondsFormat, Utc};
+use log::warn;
+
+/// Where the container output of a single project goes.
+///
+/// Every line is prefixed with the project name, so the output of projects that are built in
+/// parallel can still be told apart.
+#[derive(Debug)]
+pub struct ProjectOutput {
+    prefix: String,
+    log: Option<Mutex<ProjectLog>>,
+}
+
+/// Log file the output is also written to, with timestamps. Only created once there is output,
+/// so projects that aren't rebuilt keep the log of their last build
+#[derive(Debug)]
+struct ProjectLog {
+    path: PathBuf,
+    file: Option<fs::File>,
+    /// Set once creating the file failed, so that's only warned about once
+    failed: bool,
+}
+
+impl ProjectOutput {
+    pub fn new(project_name: &str) -> Self {
+        Self {
+            prefix: format!("[{project_name}]"),
+            log: None,
+        }
+    }
+
+    /// Like [`ProjectOutput::new`], but also writes every line to `log_path`
+    pub fn with_log(project_name: &str, log_path: PathBuf) -> Self {
+        Self {
+            log: Some(Mutex::new(ProjectLog {
+                path: log_path,
+                file: None,
+                failed: false,
+            })),
+            ..Self::new(project_name)
+        }
+    }
+
+    pub fn line(&self, line: &str) {
+        // Locking makes sure lines of different projects don't get interleaved
+        let _ = writeln!(io::stderr().lock(), "{} {line}", self.prefix);
+
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            if let Some(file) = log.open() {
+                let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+                let _ = writeln!(file, "{timestamp} {line}");
+            }
+        }
+    }
+
+    /// Forwards `reader` line by line until EOF
+    pub fn forward(&self, reader: impl Read) -> io::Result<()> {
+        for_each_line(reader, |x| self.line(x))
+    }
+
+    /// Path and last `n` lines of the log, if anything was logged by this build
+    pub fn log_tail(&self, n: usize) -> Option<(PathBuf, Vec<String>)> {
+        let log = self.log.as_ref()?.lock().unwrap();
+        log.file.as_ref()?;
+        let content = fs::read_to_string(&log.path).ok()?;
+        let lines = content.lines().collect::<Vec<_>>();
+        let tail = lines[lines.len().saturating_sub(n)..]
+            .iter()
+            .map(|&x| x.to_owned())
+            .collect();
+        Some((log.path.clone(), tail))
+    }
+}
+
+impl ProjectLog {
+    /// Creates the log file on first use, replacing the log of the previous build
+    fn open(&mut self) -> Option<&mut fs::File> {
+        if self.file.is_none() && !self.failed {
+            match create_log_file(&self.path) {
+                Ok(x) => self.file = Some(x),
+                Err(e) => {
+                    warn!("Couldn't create log file {:?}: {e}", self.path);
+                    self.failed = true;
+                }
+            }
+        }
+        self.file.as_mut()
+    }
+}
+
+/// Calls `f` with every line of `reader` until EOF, without the line ending. Output isn't
+/// necessarily UTF-8, invalid bytes are replaced
+pub fn for_each_line(reader: impl Read, mut f: impl FnMut(&str)) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+        f(String::from_utf8_lossy(&buf).trim_end());
+    }
+}
+
+fn create_log_file(path: &Path) -> io::Result<fs::File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::File::create(path)
+}
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+
+use super::{bin_builder, prev_project_info, project_src_root, BuildContext};
+use crate::config::BobConfig;
+
+/// Prints what `bob build` would do for the selected projects, without building anything
+pub fn print_plan(
+    ctx: &BuildContext,
+    build_configs: &[(PathBuf, BobConfig)],
+    selected: &[usize],
+    show_dockerfile: bool,
+) -> anyhow::Result<()> {
+    let mut rebuild_count = 0;
+
+    for &i in selected {
+        let (bob_toml_path, build_config) = &build_configs[i];
+        let project_name = &build_config.project_name;
+
+        let proj_src_root_dir = project_src_root(bob_toml_path)?;
+        let (cache_key, dockerfile_content) =
+            bin_builder::cache_key(&proj_src_root_dir, build_config)
+                .context(format!("Computing cache key of project {project_name}"))?;
+
+        let mut changes = cache_key
+            .changes_since(prev_project_info(ctx, build_config).and_then(|x| x.cache_key.as_ref()));
+        if ctx.force {
+            changes.insert(0, "forced".to_owned());
+        }
+
+        if changes.is_empty() {
+            println!("{project_name}: up to date");
+        } else {
+            rebuild_count += 1;
+            println!("{project_name}: rebuild ({})", changes.join(", "));
+        }
+        println!("  config: {bob_toml_path:?}");
+        println!("  source: {proj_src_root_dir:?}");
+        println!("  output: {:?}", ctx.out_dir.join(project_name));
+        println!("  cache key: {:016x}", cache_key.combined());
+
+        if show_dockerfile {
+            println!("  dockerfile:");
+            for line in dockerfile_content.lines() {
+                println!("    {line}");
+            }
+        }
+    }
+
+    println!(
+        "{rebuild_count} of {} selected projects would be rebuilt",
+        selected.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::{fmt::Display, path::PathBuf, time::Duration};
+
+use super::runtime::LimitExceeded;
+
+/// The step of a project build, attached as context to build errors so the summary can tell
+/// where a project failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStep {
+    Prepare,
+    BuildImage,
+    RunImage,
+    BotTomls,
+    Install,
+}
+
+impl Display for BuildStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Prepare => "preparing build",
+            Self::BuildImage => "building image",
+            Self::RunImage => "running image",
+            Self::BotTomls => "writing bot tomls",
+            Self::Install => "installing output",
+        })
+    }
+}
+
+pub enum Outcome {
+    Built,
+    /// Cache key matched
+    Skipped,
+    Failed(anyhow::Error),
+    /// Never started because an earlier build failed, or stopped by Ctrl-C
+    Cancelled,
+    /// Filtered out by `--only`/`--exclude`
+    Unselected,
+}
+
+pub struct ProjectReport {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    /// Path and last lines of the log of a failed build
+    pub failure_log: Option<(PathBuf, Vec<String>)>,
+}
+
+impl ProjectReport {
+    /// The step the project failed at, if it failed
+    pub fn failed_step(&self) -> Option<BuildStep> {
+        match &self.outcome {
+            Outcome::Failed(e) => Some(e.downcast_ref().copied().unwrap_or(BuildStep::Prepare)),
+            _ => None,
+        }
+    }
+}
+
+/// Prints a table of all projects and what happened to them, followed by the full error chain of
+/// every failed project
+pub fn print_summary(reports: &[ProjectReport]) {
+    let name_width = reports
+        .iter()
+        .map(|x| x.name.len())
+        .chain(["PROJECT".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:name_width$}  {:9}  {:>9}  DETAILS",
+        "PROJECT", "STATUS", "DURATION"
+    );
+    for report in reports {
+        if matches!(report.outcome, Outcome::Unselected) {
+            continue;
+        }
+        let (status, details) = match &report.outcome {
+            Outcome::Built => ("built", String::new()),
+            Outcome::Skipped => ("skipped", "cache key matched".to_owned()),
+            Outcome::Failed(e) => (
+                match e.downcast_ref::<LimitExceeded>() {
+                    Some(LimitExceeded::Timeout(_)) => "timed out",
+                    Some(LimitExceeded::OutOfMemory(_)) => "oom",
+                    None => "failed",
+                },
+                format!(
+                    "failed while {}: {}",
+                    report.failed_step().unwrap(),
+                    e.root_cause()
+                ),
+            ),
+            Outcome::Cancelled => ("cancelled", String::new()),
+            Outcome::Unselected => unreachable!(),
+        };
+        println!(
+            "{:name_width$}  {status:9}  {:>8.1}s  {details}",
+            report.name,
+            report.duration.as_secs_f64()
+        );
+    }
+
+    let count = |f: fn(&Outcome) -> bool| reports.iter().filter(|x| f(&x.outcome)).count();
+    println!(
+        "{} built, {} skipped, {} failed, {} cancelled, {} not selected",
+        count(|x| matches!(x, Outcome::Built)),
+        count(|x| matches!(x, Outcome::Skipped)),
+        count(|x| matches!(x, Outcome::Failed(_))),
+        count(|x| matches!(x, Outcome::Cancelled)),
+        count(|x| matches!(x, Outcome::Unselected)),
+    );
+
+    for report in reports {
+        if let Outcome::Failed(e) = &report.outcome {
+            println!("\n{} failed:\n{e:?}", report.name);
+            if let Some((path, lines)) = &report.failure_log {
+                println!("\nLast {} line(s) of {path:?}:", lines.len());
+                for line in lines {
+                    println!("{line}");
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,16 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    fmt::Display,
+    hash::{Hash, Hasher as _},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use chrono::{DateTime, Utc};
+use rapidhash::RapidInlineHasher;
 use serde::{Deserialize, Serialize};
 
+use crate::platform::Os;
+
 mod hex_ser {
     use serde::{de::Error, Deserialize, Deserializer, Serializer};
 
@@ -29,10 +37,69 @@ pub struct BuildInfo {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
     pub name: String,
+    /// Combined hash of [`CacheKey`], older versions of bob only hashed the source files
     #[serde(with = "hex_ser")]
     pub hash: u64,
     #[serde(with = "toml_datetime_compat")]
     pub build_date: DateTime<Utc>,
+    /// Platforms declared in bob.toml, see [`crate::config::BobConfig::platforms`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platforms: Option<Vec<Os>>,
+    /// Missing for projects built by older versions of bob
+    #[serde(default)]
+    pub cache_key: Option<CacheKey>,
+    /// Container output of the last build, relative to the output dir. A failed build
+    /// replaces it too
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_file: Option<PathBuf>,
+}
+
+/// Everything that can change the output of a project build
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    /// Hash of the project source files, see [`bob_lib::dirhasher`]
+    #[serde(with = "hex_ser")]
+    pub source: u64,
+    /// Hash of the rendered Dockerfile
+    #[serde(with = "hex_ser")]
+    pub dockerfile: u64,
+    /// Hash of the project's entry in bob.toml
+    #[serde(with = "hex_ser")]
+    pub config: u64,
+    pub bob_version: String,
+}
+
+impl CacheKey {
+    pub fn combined(&self) -> u64 {
+        let mut hasher = RapidInlineHasher::default();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Human readable reasons for why a project with this key needs to be rebuilt
+    pub fn changes_since(&self, prev: Option<&CacheKey>) -> Vec<String> {
+        let Some(prev) = prev else {
+            return vec!["no previous build".to_owned()];
+        };
+
+        let mut changes = vec![];
+        if self.source != prev.source {
+            changes.push("source files changed".to_owned());
+        }
+        if self.dockerfile != prev.dockerfile {
+            changes.push("generated Dockerfile changed".to_owned());
+        }
+        if self.config != prev.config {
+            changes.push("bob.toml config changed".to_owned());
+        }
+        if self.bob_version != prev.bob_version {
+            changes.push(format!(
+                "bob version changed ({} -> {})",
+                prev.bob_version, self.bob_version
+            ));
+        }
+        changes
+    }
 }
 
 impl FromStr for BuildInfo {
@@ -47,11 +114,3 @@ impl Display for BuildInfo {
         write!(f, "{}", toml::to_string_pretty(self).unwrap())
     }
 }
-
-impl BuildInfo {
-    pub fn new() -> Self {
-        BuildInfo {
-            projects: Default::default(),
-        }
-    }
-}
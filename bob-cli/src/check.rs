@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Context};
+use log::{error, info};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::build::{bin_builder, project_src_root};
+use crate::config::{read_build_configs, BobConfig, BuilderConfigVariant};
+
+/// A problem with a config, pointing at the file and key it's in
+struct Problem {
+    file: PathBuf,
+    key: String,
+    message: String,
+}
+
+/// Validates a bob.toml tree and the bot configs it references without building anything
+pub fn command_check(config_path: PathBuf) -> anyhow::Result<()> {
+    if !fs::exists(&config_path)? {
+        return Err(anyhow!("File doesn't exist"));
+    }
+
+    let build_configs = read_build_configs(config_path)?;
+
+    let mut problems = vec![];
+    for (bob_toml_path, build_config) in &build_configs {
+        check_project(bob_toml_path, build_config, &mut problems)?;
+    }
+
+    for problem in &problems {
+        error!("{:?}: {}: {}", problem.file, problem.key, problem.message);
+    }
+    if !problems.is_empty() {
+        return Err(anyhow!("Found {} problem(s)", problems.len()));
+    }
+
+    info!("All {} project(s) look good", build_configs.len());
+    Ok(())
+}
+
+fn check_project(
+    bob_toml_path: &Path,
+    build_config: &BobConfig,
+    problems: &mut Vec<Problem>,
+) -> anyhow::Result<()> {
+    let project_root = project_src_root(bob_toml_path)?;
+    let mut problem = |key: String, message: String| {
+        problems.push(Problem {
+            file: bob_toml_path.to_owned(),
+            key: format!("{}.{key}", build_config.project_name),
+            message,
+        })
+    };
+
+    for (i, bot_config) in build_config.bot_configs.iter().enumerate() {
+        let key = format!("bot_configs[{i}]");
+        let bot_toml_path = project_root.join(bot_config);
+        match bot_toml_path.canonicalize() {
+            Ok(x) if !x.starts_with(&project_root) => problem(
+                key,
+                format!("{bot_config:?} is outside of project root {project_root:?}"),
+            ),
+            Ok(x) => {
+                for (bot_key, message) in check_bot_toml(&x) {
+                    let key = format!("{key} ({bot_config:?}) {bot_key}");
+                    problem(key.trim_end().to_owned(), message);
+                }
+            }
+            Err(e) => problem(key, format!("{bot_config:?} doesn't exist: {e}")),
+        }
+    }
+
+    if let Err(e) = bin_builder::check_secret_declarations(&project_root, &build_config.secrets) {
+        problem("secrets".to_owned(), e.to_string());
+    }
+
+    if let BuilderConfigVariant::Custom(custom) = &build_config.builder_config {
+        let dockerfile_path = project_root.join(&custom.dockerfile);
+        if !dockerfile_path.is_file() {
+            problem(
+                "builder_config.dockerfile".to_owned(),
+                format!("{:?} doesn't exist", custom.dockerfile),
+            );
+            return Ok(());
+        }
+    }
+
+    // Renders the template, so syntax errors and values missing from the template context show up
+    if let Err(e) = bin_builder::generate_dockerfile(build_config, &project_root) {
+        problem("builder_config".to_owned(), format!("{e:#}"));
+    }
+
+    Ok(())
+}
+
+/// Returns the problems of a bot.toml as (key, message) pairs
+fn check_bot_toml(bot_toml_path: &Path) -> Vec<(String, String)> {
+    let toml_bot_config = match fs::read_to_string(bot_toml_path)
+        .context("couldn't read file")
+        .and_then(|x| toml::from_str::<toml::Table>(&x).context("couldn't parse file"))
+    {
+        Ok(x) => x,
+        Err(e) => return vec![(String::new(), format!("{e:#}"))],
+    };
+
+    let Some(settings_table) = toml_bot_config.get("settings") else {
+        return vec![("settings".to_owned(), "missing [settings] table".to_owned())];
+    };
+    let Some(settings_table) = settings_table.as_table() else {
+        return vec![("settings".to_owned(), "isn't a table".to_owned())];
+    };
+
+    let bot_toml_parent = bot_toml_path.parent().unwrap();
+    let mut problems = vec![];
+    for key in ["logo_file", "loadout_file"] {
+        match settings_table.get(key) {
+            None => {}
+            Some(toml::Value::String(path)) if bot_toml_parent.join(path).is_file() => {}
+            Some(toml::Value::String(path)) => problems.push((
+                format!("settings.{key}"),
+                format!("{path:?} doesn't exist next to the bot.toml"),
+            )),
+            Some(_) => problems.push((format!("settings.{key}"), "isn't a string".to_owned())),
+        }
+    }
+    problems
+}
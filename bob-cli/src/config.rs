@@ -1,26 +1,116 @@
 use std::{
     borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
-    str::FromStr,
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::build::runtime::{ResourceLimits, RuntimeKind};
+use crate::interpolate::{interpolate_table, read_env_file, Variables};
+use crate::platform::Os;
+use crate::units::{ByteSize, HumanDuration};
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct RootConfig {
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub dependencies: Vec<PathBuf>,
+    /// Only respected in the bob.toml passed to bob, not in dependencies
+    pub runtime: Option<RuntimeKind>,
     #[serde(rename = "config", default)]
     pub configs: Vec<BobConfig>,
+    /// Builder config values per `builder_type`, used by the projects in this bob.toml and its
+    /// dependencies unless they set them themselves. Defaults of dependencies override these
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    #[schemars(with = "BuilderDefaults")]
+    pub defaults: Defaults,
+    /// File with `KEY=VALUE` lines, relative to this bob.toml. Its variables can be used for
+    /// `${VAR}` interpolation in this bob.toml and its dependencies, the environment takes
+    /// precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<PathBuf>,
+    /// Variables of the env files of this bob.toml and the ones including it
+    #[serde(skip)]
+    pub variables: Variables,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Builder config values per `builder_type`
+pub type Defaults = BTreeMap<String, toml::Table>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct BobConfig {
     pub project_name: String,
     pub bot_configs: Vec<PathBuf>,
     pub builder_config: BuilderConfigVariant,
+    /// Overrides the detection of entry binaries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<EntryConfig>,
+    /// Platforms the project ships binaries for. If left out, windows binaries are required and
+    /// linux ones are optional
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platforms: Option<Vec<Os>>,
+    /// Also write a run command per architecture to the bot tomls, like
+    /// `run_command_linux_aarch64`, which `bob split` picks from. Always done for an OS the
+    /// project has binaries of more than one architecture for
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub per_arch_run_commands: bool,
+    /// Passed to the container build as `--build-arg`s and available to the Dockerfile template
+    /// as `build_args`. Use `${VAR}` to take values from the environment or an env file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_args: BTreeMap<String, String>,
+    /// BuildKit secrets by id, for Dockerfiles to mount with `RUN --mount=type=secret,id=...`.
+    /// Only where the values come from is part of the config, never the values themselves
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub secrets: BTreeMap<String, SecretSource>,
+    /// How long building and running the image may take, like `30m` or `1h30m`. Defaults to
+    /// `bob build --timeout`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub timeout: Option<HumanDuration>,
+    /// How many CPUs the containers may use, like `1.5`. Defaults to `bob build --cpus`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+    /// How much memory the containers may use, like `512M` or `4G`. Defaults to
+    /// `bob build --memory`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
+    pub memory: Option<ByteSize>,
+}
+
+impl BobConfig {
+    /// The limits set in bob.toml, without the defaults from the command line
+    pub fn resource_limits(&self) -> ResourceLimits {
+        ResourceLimits::new(
+            self.timeout.map(|x| x.0),
+            self.cpus,
+            self.memory.map(|x| x.0),
+        )
+    }
+}
+
+/// Where the value of a secret comes from, e.g. `{ env = "GITHUB_TOKEN" }` or
+/// `{ file = "secrets/token" }`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// Name of an env var of bob
+    Env(String),
+    /// Path relative to the project root
+    File(PathBuf),
+}
+
+/// Paths of the entry binaries relative to the built project dir, e.g.
+/// `x86_64-pc-windows-msvc/bot.exe`. Globs are allowed, but must match exactly one file.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct EntryConfig {
+    pub windows: Option<String>,
+    pub linux: Option<String>,
 }
 
 pub trait BuilderConfig: erased_serde::Serialize {
@@ -30,7 +120,7 @@ erased_serde::serialize_trait_object!(BuilderConfig);
 
 macro_rules! builder_configs {
     ($($i:ident $rename:literal $get_path:expr => $struct_contents:tt),+) => {
-        #[derive(Debug, Clone, Serialize, Deserialize)]
+        #[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
         #[serde(tag = "builder_type")]
         pub enum BuilderConfigVariant {
             $(
@@ -38,6 +128,18 @@ macro_rules! builder_configs {
                 $i(builder_configs::$i)
             ),+
         }
+        /// The `builder_type`s there are
+        pub const BUILDER_TYPES: &[&str] = &[$($rename),+];
+        /// Schema of `[defaults]`, which has the fields of a builder config per `builder_type`
+        #[derive(JsonSchema)]
+        #[schemars(deny_unknown_fields)]
+        #[allow(non_snake_case, dead_code)]
+        struct BuilderDefaults {
+            $(
+                #[schemars(rename = $rename)]
+                $i: Option<builder_configs::$i>
+            ),+
+        }
         impl BuilderConfigVariant {
             pub fn get_inner_as_generic(&self) -> &dyn BuilderConfig {
                 match self {
@@ -51,7 +153,9 @@ macro_rules! builder_configs {
             // Allows invocations of the macro to use imported types
             use super::*;
             $(
-                #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+                #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema)]
+                #[serde(deny_unknown_fields)]
+                #[schemars(transform = optional_fields)]
                 pub struct $i $struct_contents
                 impl super::BuilderConfig for $i {
                     fn get_dockerfile_contents(&self, project_root: &Path)
@@ -64,6 +168,12 @@ macro_rules! builder_configs {
     };
 }
 
+/// Builder config fields can be left out when `[defaults]` sets them, so the schema doesn't
+/// require any
+fn optional_fields(schema: &mut schemars::Schema) {
+    schema.remove("required");
+}
+
 builder_configs!(
     // InternalName "toml_name"
     // |_self: builder_configs::InternalName, project_root: &Path|
@@ -74,18 +184,19 @@ builder_configs!(
     PyInstaller "pyinstaller"
     |_,_| Ok(include_str!("../dockerfiles/pyinstaller.Dockerfile"))
     => {
-        entry_file: PathBuf,
+        pub entry_file: PathBuf,
     },
     Rust "rust"
     |_,_| Ok(include_str!("../dockerfiles/rust.Dockerfile"))
     => {
-        bin_name: String,
-        targets: Vec<String>,
+        pub bin_name: String,
+        pub targets: Vec<String>,
     },
     Custom "custom"
     |s,r| {get_custom_dockerfile_contents(s,r)}
     => {
         pub dockerfile: PathBuf,
+        #[schemars(with = "Option<serde_json::Value>")]
         pub values: Option<serde_value::Value>,
     }
 );
@@ -103,21 +214,192 @@ fn get_custom_dockerfile_contents(
 impl std::str::FromStr for RootConfig {
     type Err = anyhow::Error; // TODO: this feels wrong
     fn from_str(input: &str) -> anyhow::Result<Self> {
-        toml::from_str(input).context("Parsing bob config failed")
+        Self::parse(input, Path::new("."), &Defaults::new(), &Variables::new())
+    }
+}
+
+impl RootConfig {
+    /// Parses a bob.toml in `config_dir`, interpolating variables and filling in the builder
+    /// configs of its projects from `inherited_defaults` and its own `[defaults]`. The returned
+    /// config's `defaults` and `variables` are the merged ones, to be passed on to its
+    /// dependencies
+    fn parse(
+        input: &str,
+        config_dir: &Path,
+        inherited_defaults: &Defaults,
+        inherited_variables: &Variables,
+    ) -> anyhow::Result<Self> {
+        let mut table: toml::Table = toml::from_str(input).context("Parsing bob config failed")?;
+
+        let mut variables = inherited_variables.clone();
+        if let Some(env_file) = table.get("env_file").and_then(|x| x.as_str()) {
+            variables.extend(read_env_file(&config_dir.join(env_file))?);
+        }
+        let interpolated =
+            interpolate_table(&mut table, &variables).context("Interpolating variables failed")?;
+
+        let own_defaults: Defaults = table
+            .remove("defaults")
+            .map(|x| x.try_into())
+            .transpose()
+            .context("Parsing defaults failed, expected a table per builder type")?
+            .unwrap_or_default();
+        if let Some(builder_type) = own_defaults
+            .keys()
+            .find(|x| !BUILDER_TYPES.contains(&x.as_str()))
+        {
+            return Err(anyhow!(
+                "Unknown builder type {builder_type:?} in defaults, expected one of {}",
+                BUILDER_TYPES.join(", ")
+            ));
+        }
+        let mut defaults = inherited_defaults.clone();
+        for (builder_type, values) in own_defaults {
+            let merged = merge_tables(defaults.remove(&builder_type).unwrap_or_default(), values);
+            defaults.insert(builder_type, merged);
+        }
+
+        if defaults.is_empty() && !interpolated {
+            // Parsing the string directly keeps line numbers in errors
+            let mut root_config: Self =
+                toml::from_str(input).context("Parsing bob config failed")?;
+            root_config.variables = variables;
+            return Ok(root_config);
+        }
+
+        let configs = table
+            .get_mut("config")
+            .and_then(|x| x.as_array_mut())
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.as_table_mut());
+        for config in configs {
+            let Some(toml::Value::Table(builder_config)) = config.get_mut("builder_config") else {
+                continue;
+            };
+            let Some(default) = builder_config
+                .get("builder_type")
+                .and_then(|x| x.as_str())
+                .and_then(|x| defaults.get(x))
+            else {
+                continue;
+            };
+            *builder_config = merge_tables(default.clone(), std::mem::take(builder_config));
+        }
+
+        let mut root_config: Self = toml::Value::Table(table)
+            .try_into()
+            .context("Parsing bob config failed")?;
+        root_config.defaults = defaults;
+        root_config.variables = variables;
+        Ok(root_config)
     }
 }
 
+/// Merges `overrides` into `base`, tables in both are merged recursively
+fn merge_tables(mut base: toml::Table, overrides: toml::Table) -> toml::Table {
+    for (key, value) in overrides {
+        let merged = match (base.remove(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => {
+                toml::Value::Table(merge_tables(base, value))
+            }
+            (_, value) => value,
+        };
+        base.insert(key, merged);
+    }
+    base
+}
+
+pub fn read_root_config(config_path: &Path) -> anyhow::Result<RootConfig> {
+    read_root_config_inheriting(config_path, &Defaults::new(), &Variables::new())
+}
+
+/// Reads a bob.toml that is a dependency of bob.tomls with `inherited_defaults` and
+/// `inherited_variables`
+fn read_root_config_inheriting(
+    config_path: &Path,
+    inherited_defaults: &Defaults,
+    inherited_variables: &Variables,
+) -> anyhow::Result<RootConfig> {
+    let canonical_config_path = config_path.canonicalize()?;
+
+    let str_content = fs::read_to_string(config_path)
+        .context(format!("reading bob config at {:?}", canonical_config_path))?;
+    RootConfig::parse(
+        &str_content,
+        canonical_config_path.parent().unwrap(),
+        inherited_defaults,
+        inherited_variables,
+    )
+    .context(format!("parsing bob config at {:?}", canonical_config_path))
+}
+
+/// Finds a project by name, the error lists all available projects
+pub fn find_project<'a>(
+    build_configs: &'a [(PathBuf, BobConfig)],
+    project_name: &str,
+) -> anyhow::Result<&'a (PathBuf, BobConfig)> {
+    build_configs
+        .iter()
+        .find(|(_, x)| x.project_name == project_name)
+        .ok_or_else(|| {
+            anyhow!(
+                "No project named {project_name:?}, available projects: {}",
+                build_configs
+                    .iter()
+                    .map(|(_, x)| x.project_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// Reads the configs of all projects in `root_config_path` and its dependencies, recursively.
+///
+/// bob.tomls included more than once are only read once, include cycles and projects with the
+/// same name are errors.
 pub fn read_build_configs(root_config_path: PathBuf) -> anyhow::Result<Vec<(PathBuf, BobConfig)>> {
+    struct State {
+        configs: Vec<(PathBuf, BobConfig)>,
+        /// Canonical paths of all bob.tomls read so far
+        visited: HashSet<PathBuf>,
+        /// Canonical paths of the bob.tomls currently being read, each one included by the
+        /// previous one
+        include_chain: Vec<PathBuf>,
+        /// Which bob.toml defined each project
+        defined_in: HashMap<String, PathBuf>,
+    }
+
     fn recurse(
         config_path: PathBuf,
-        configs: &mut Vec<(PathBuf, BobConfig)>,
+        defaults: &Defaults,
+        variables: &Variables,
+        state: &mut State,
     ) -> anyhow::Result<()> {
-        let canonical_config_path = config_path.canonicalize()?;
+        let canonical_config_path = config_path
+            .canonicalize()
+            .context(format!("reading bob config at {config_path:?}"))?;
+        if let Some(cycle_start) = state
+            .include_chain
+            .iter()
+            .position(|x| *x == canonical_config_path)
+        {
+            let cycle = state.include_chain[cycle_start..]
+                .iter()
+                .chain([&canonical_config_path])
+                .map(|x| format!("{x:?}"))
+                .collect::<Vec<_>>();
+            return Err(anyhow!(
+                "bob.toml dependencies include each other: {}",
+                cycle.join(" -> ")
+            ));
+        }
+        if !state.visited.insert(canonical_config_path.clone()) {
+            // Already included through another path
+            return Ok(());
+        }
 
-        let str_content = fs::read_to_string(&config_path)
-            .context(format!("reading bob config at {:?}", canonical_config_path))?;
-        let root_config = RootConfig::from_str(&str_content)
-            .context(format!("parsing bob config at {:?}", canonical_config_path))?;
+        let root_config = read_root_config_inheriting(&config_path, defaults, variables)?;
 
         let config_path_parent = config_path.parent().unwrap().to_owned();
         let dep_paths = root_config
@@ -129,15 +411,64 @@ pub fn read_build_configs(root_config_path: PathBuf) -> anyhow::Result<Vec<(Path
             })
             .collect::<Result<Vec<_>, _>>()?;
         for build_config in root_config.configs {
-            configs.push((config_path.clone(), build_config));
+            if let Some(other_path) = state.defined_in.insert(
+                build_config.project_name.clone(),
+                canonical_config_path.clone(),
+            ) {
+                return Err(anyhow!(
+                    "project {:?} is defined in both {other_path:?} and {canonical_config_path:?}",
+                    build_config.project_name
+                ));
+            }
+            state.configs.push((config_path.clone(), build_config));
         }
+
+        state.include_chain.push(canonical_config_path);
         for dep in dep_paths {
-            recurse(dep, configs)?
+            recurse(dep, &root_config.defaults, &root_config.variables, state)?
         }
+        state.include_chain.pop();
         Ok(())
     }
 
-    let mut configs: Vec<(PathBuf, BobConfig)> = vec![];
-    recurse(root_config_path, &mut configs)?;
-    Ok(configs)
+    let mut state = State {
+        configs: vec![],
+        visited: HashSet::new(),
+        include_chain: vec![],
+        defined_in: HashMap::new(),
+    };
+    recurse(
+        root_config_path,
+        &Defaults::new(),
+        &Variables::new(),
+        &mut state,
+    )?;
+    Ok(state.configs)
+}
+
+/// Prints the effective config of a project, with the defaults it inherited filled in
+pub fn command_config_show(config_path: PathBuf, project_name: &str) -> anyhow::Result<()> {
+    if !fs::exists(&config_path)? {
+        return Err(anyhow!("File doesn't exist"));
+    }
+
+    let build_configs = read_build_configs(config_path)?;
+    let (bob_toml_path, build_config) = find_project(&build_configs, project_name)?;
+
+    // Printed as a root config so it can be pasted into a bob.toml as is
+    let root_config = RootConfig {
+        dependencies: vec![],
+        runtime: None,
+        configs: vec![build_config.clone()],
+        defaults: Defaults::new(),
+        env_file: None,
+        variables: Variables::new(),
+    };
+    println!("# Defined in {bob_toml_path:?}");
+    print!(
+        "{}",
+        toml::to_string_pretty(&root_config).context("Serializing config")?
+    );
+
+    Ok(())
 }
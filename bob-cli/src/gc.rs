@@ -0,0 +1,243 @@
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    build::{
+        log_path_relative,
+        runtime::{ContainerRuntime, ImageInfo},
+        write_build_info, BUILDINFO_PATH_RELATIVE, LOGS_PATH_RELATIVE, OLD_PATH_RELATIVE,
+        STAGING_PATH_RELATIVE,
+    },
+    buildinfo::BuildInfo,
+    config::{read_build_configs, read_root_config},
+    GcCommand,
+};
+
+/// Something `bob gc` removes
+enum Garbage {
+    Image(String),
+    Dir(PathBuf),
+    File(PathBuf),
+}
+
+/// Removes images and outputs in `out_dir` that no project of the config tree uses anymore
+pub fn command_gc(gc_command: GcCommand) -> anyhow::Result<()> {
+    if !fs::exists(&gc_command.config_path)? {
+        return Err(anyhow!("File doesn't exist"));
+    }
+    let config_path = fs::canonicalize(&gc_command.config_path)
+        .context(format!("Couldn't resolve {:?}", gc_command.config_path))?;
+
+    let root_config = read_root_config(&gc_command.config_path)?;
+    let runtime_kind = gc_command
+        .runtime
+        .or(root_config.runtime)
+        .unwrap_or_default();
+    info!("Using container runtime {runtime_kind:?}");
+    let runtime = runtime_kind.instantiate();
+
+    let project_names = read_build_configs(gc_command.config_path.clone())?
+        .into_iter()
+        .map(|(_, x)| x.project_name)
+        .collect::<BTreeSet<_>>();
+
+    // Makes sure out_dir really is an output dir, everything bob removes from it is listed here
+    let build_info_path = gc_command.out_dir.join(BUILDINFO_PATH_RELATIVE);
+    let mut build_info = fs::read_to_string(&build_info_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| BuildInfo::from_str(&s))
+        .context(format!(
+            "{:?} isn't a bob output dir, couldn't read its buildinfo.toml",
+            gc_command.out_dir
+        ))?;
+
+    // Hashes of the builds in out_dir, their images are never removed
+    let current_hashes = build_info
+        .projects
+        .iter()
+        .filter(|x| project_names.contains(&x.name))
+        .map(|x| x.hash)
+        .collect::<BTreeSet<_>>();
+
+    let mut garbage = vec![];
+    for (image, reason) in obsolete_images(
+        runtime.as_ref(),
+        &config_path,
+        &project_names,
+        &current_hashes,
+        gc_command.keep,
+    )? {
+        info!("Image {}: {reason}", image.tag);
+        garbage.push(Garbage::Image(image.tag));
+    }
+
+    for (path, reason) in orphaned_outputs(&gc_command.out_dir, &build_info, &project_names)? {
+        info!("{path:?}: {reason}");
+        garbage.push(if path.is_dir() && !path.is_symlink() {
+            Garbage::Dir(path)
+        } else {
+            Garbage::File(path)
+        });
+    }
+
+    let stale_build_info = build_info
+        .projects
+        .iter()
+        .any(|x| !project_names.contains(&x.name));
+    if stale_build_info {
+        info!("{build_info_path:?}: has entries of projects that aren't in the config tree");
+    }
+
+    if gc_command.dry_run {
+        info!(
+            "Would remove {} item(s){}",
+            garbage.len(),
+            if stale_build_info {
+                " and clean up buildinfo.toml"
+            } else {
+                ""
+            }
+        );
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for item in &garbage {
+        let result = match item {
+            Garbage::Image(tag) => runtime
+                .remove_image(tag)
+                .context(format!("Couldn't remove image {tag}")),
+            Garbage::Dir(path) => {
+                fs::remove_dir_all(path).context(format!("Couldn't remove {path:?}"))
+            }
+            Garbage::File(path) => {
+                fs::remove_file(path).context(format!("Couldn't remove {path:?}"))
+            }
+        };
+        if let Err(e) = result {
+            warn!("{e:#}");
+            failed += 1;
+        }
+    }
+    if stale_build_info {
+        // Entries whose output couldn't be removed are kept, so the next run still finds it
+        build_info.projects.retain(|x| {
+            project_names.contains(&x.name) || gc_command.out_dir.join(&x.name).exists()
+        });
+        write_build_info(&gc_command.out_dir, &build_info)?;
+    }
+
+    if failed > 0 {
+        return Err(anyhow!(
+            "Couldn't remove {failed} of {} item(s)",
+            garbage.len()
+        ));
+    }
+    info!("Removed {} item(s)", garbage.len());
+    Ok(())
+}
+
+/// Images of the config tree at `config_path` whose project isn't in it anymore, and all but the
+/// newest `keep` images of the others. Returned with the reason they are obsolete
+fn obsolete_images(
+    runtime: &dyn ContainerRuntime,
+    config_path: &Path,
+    project_names: &BTreeSet<String>,
+    current_hashes: &BTreeSet<u64>,
+    keep: usize,
+) -> anyhow::Result<Vec<(ImageInfo, String)>> {
+    let mut images_by_project: BTreeMap<String, Vec<ImageInfo>> = BTreeMap::new();
+    for image in runtime.list_images().context("Couldn't list images")? {
+        // Images of other config trees and unlabeled ones built by older versions of bob are
+        // left alone, there is no telling if something still uses them
+        if image.config_path.as_deref() != Some(config_path) {
+            continue;
+        }
+        let Some(project_name) = image.project_name.clone() else {
+            continue;
+        };
+        images_by_project
+            .entry(project_name)
+            .or_default()
+            .push(image);
+    }
+
+    let mut obsolete = vec![];
+    for (project_name, mut images) in images_by_project {
+        let is_current =
+            |image: &ImageInfo| image.hash().is_some_and(|x| current_hashes.contains(&x));
+        if !project_names.contains(&project_name) {
+            for image in images {
+                obsolete.push((
+                    image,
+                    format!("project {project_name:?} isn't in the config tree"),
+                ));
+            }
+            continue;
+        }
+
+        images.sort_by_key(|x| Reverse(x.created));
+        for image in images.into_iter().skip(keep).filter(|x| !is_current(x)) {
+            obsolete.push((
+                image,
+                format!("older than the newest {keep} image(s) of {project_name:?}"),
+            ));
+        }
+    }
+    Ok(obsolete)
+}
+
+/// Entries of `out_dir` bob wrote that the config tree doesn't use anymore, with the reason they
+/// are orphaned. Only output dirs of projects in `build_info` and bob's own dirs are considered,
+/// anything else in `out_dir` isn't bob's to remove
+fn orphaned_outputs(
+    out_dir: &Path,
+    build_info: &BuildInfo,
+    project_names: &BTreeSet<String>,
+) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let mut orphaned = vec![];
+    for entry in fs::read_dir(out_dir).context(format!("reading {out_dir:?}"))? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = entry.file_name();
+        let is_dir = entry.file_type()?.is_dir();
+
+        if is_dir && path == out_dir.join(LOGS_PATH_RELATIVE) {
+            for entry in fs::read_dir(&path).context(format!("reading {path:?}"))? {
+                let path = entry?.path();
+                let has_project = project_names
+                    .iter()
+                    .any(|x| path == out_dir.join(log_path_relative(x)));
+                if !has_project {
+                    orphaned.push((
+                        path,
+                        "no project with this log in the config tree".to_owned(),
+                    ));
+                }
+            }
+        } else if path == out_dir.join(STAGING_PATH_RELATIVE)
+            || path == out_dir.join(OLD_PATH_RELATIVE)
+        {
+            // Builds remove it once they are done
+            orphaned.push((path, "left over from an interrupted build".to_owned()));
+        } else if let Some(name) = name.to_str().filter(|x| {
+            is_dir
+                && !project_names.contains(*x)
+                && build_info.projects.iter().any(|project| project.name == *x)
+        }) {
+            orphaned.push((
+                path,
+                format!("output of project {name:?}, which isn't in the config tree"),
+            ));
+        }
+    }
+    orphaned.sort();
+    Ok(orphaned)
+}
@@ -0,0 +1,262 @@
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{builder_configs, BobConfig, BuilderConfigVariant, RootConfig};
+
+/// Targets new rust projects are built for, the rust Dockerfile can cross compile to both
+const DEFAULT_RUST_TARGETS: &[&str] = &["x86_64-pc-windows-gnu", "x86_64-unknown-linux-gnu"];
+
+/// Python files that are likely the entry point of a bot, in order of preference
+const PYTHON_ENTRY_NAMES: &[&str] = &["bot.py", "main.py", "run.py", "__main__.py"];
+
+/// Writes a bob.toml for the project in `dir`, detecting how it should be built
+pub fn command_init(
+    dir: PathBuf,
+    project_name: Option<String>,
+    register: bool,
+    force: bool,
+) -> anyhow::Result<()> {
+    let dir = dir
+        .canonicalize()
+        .context(format!("Directory {dir:?} doesn't exist"))?;
+    let bob_toml_path = dir.join("bob.toml");
+    if bob_toml_path.exists() && !force {
+        return Err(anyhow!(
+            "{bob_toml_path:?} already exists, pass --force to overwrite it"
+        ));
+    }
+
+    let project_name = match project_name {
+        Some(x) => x,
+        None => dir
+            .file_name()
+            .and_then(|x| x.to_str())
+            .context("Couldn't get project name from directory name, pass --name")?
+            .to_owned(),
+    };
+
+    let builder_config = detect_builder_config(&dir)?;
+
+    let bot_configs = find_bot_tomls(&dir)?;
+    if bot_configs.is_empty() {
+        warn!("No bot.toml found in {dir:?}, add it to `bot_configs` in bob.toml yourself");
+    }
+
+    let root_config = RootConfig {
+        dependencies: vec![],
+        runtime: None,
+        configs: vec![BobConfig {
+            project_name,
+            bot_configs,
+            builder_config,
+            entry: None,
+            platforms: None,
+            per_arch_run_commands: false,
+            build_args: Default::default(),
+            secrets: Default::default(),
+            timeout: None,
+            cpus: None,
+            memory: None,
+        }],
+        defaults: Default::default(),
+        env_file: None,
+        variables: Default::default(),
+    };
+    fs::write(
+        &bob_toml_path,
+        toml::to_string(&root_config).context("Serializing bob.toml")?,
+    )
+    .context(format!("Couldn't write {bob_toml_path:?}"))?;
+    info!("Wrote {bob_toml_path:?}");
+
+    if register {
+        register_in_parent(&bob_toml_path)?;
+    }
+
+    Ok(())
+}
+
+fn detect_builder_config(dir: &Path) -> anyhow::Result<BuilderConfigVariant> {
+    let cargo_toml_path = dir.join("Cargo.toml");
+    if cargo_toml_path.is_file() {
+        let bin_name = detect_rust_bin(&cargo_toml_path)?;
+        info!("Found Cargo.toml, using the rust builder for binary {bin_name:?}");
+        return Ok(BuilderConfigVariant::Rust(builder_configs::Rust {
+            bin_name,
+            targets: DEFAULT_RUST_TARGETS.iter().map(|&x| x.to_owned()).collect(),
+        }));
+    }
+
+    let has_requirements = dir.join("requirements.txt").is_file();
+    if has_requirements || dir.join("pyproject.toml").is_file() {
+        let entry_file = detect_python_entry(dir)?;
+        if !has_requirements {
+            warn!("The pyinstaller builder installs dependencies from requirements.txt, which this project doesn't have");
+        }
+        info!("Found a python project, using the pyinstaller builder with entry {entry_file:?}");
+        return Ok(BuilderConfigVariant::PyInstaller(
+            builder_configs::PyInstaller { entry_file },
+        ));
+    }
+
+    if dir.join("Dockerfile").is_file() {
+        info!("Found a Dockerfile, using the custom builder");
+        return Ok(BuilderConfigVariant::Custom(builder_configs::Custom {
+            dockerfile: "Dockerfile".into(),
+            values: None,
+        }));
+    }
+
+    Err(anyhow!(
+        "Couldn't detect the project type of {dir:?}, expected a Cargo.toml, requirements.txt, pyproject.toml or Dockerfile"
+    ))
+}
+
+/// Picks the binary of a cargo package, preferring the one named like the package
+fn detect_rust_bin(cargo_toml_path: &Path) -> anyhow::Result<String> {
+    let cargo_toml: toml::Table = toml::from_str(
+        &fs::read_to_string(cargo_toml_path).context(format!("reading {cargo_toml_path:?}"))?,
+    )
+    .context(format!("parsing {cargo_toml_path:?}"))?;
+
+    let package_name = cargo_toml
+        .get("package")
+        .and_then(|x| x.get("name"))
+        .and_then(|x| x.as_str());
+    let mut bins = cargo_toml
+        .get("bin")
+        .and_then(|x| x.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.get("name")?.as_str())
+        .collect::<Vec<_>>();
+    // Cargo's implicit binary
+    if let Some(package_name) = package_name {
+        if cargo_toml_path.with_file_name("src/main.rs").is_file() && !bins.contains(&package_name)
+        {
+            bins.push(package_name);
+        }
+    }
+
+    let bin_name = match bins.as_slice() {
+        [] => {
+            return Err(anyhow!(
+                "{cargo_toml_path:?} has no binaries, is it a workspace or library?"
+            ))
+        }
+        [bin] => bin,
+        _ => {
+            let bin = package_name.filter(|x| bins.contains(x)).unwrap_or(bins[0]);
+            warn!("Found multiple binaries ({}), using {bin:?}, change `bin_name` in bob.toml if that's wrong", bins.join(", "));
+            bin
+        }
+    };
+    Ok(bin_name.to_owned())
+}
+
+fn detect_python_entry(dir: &Path) -> anyhow::Result<PathBuf> {
+    if let Some(name) = PYTHON_ENTRY_NAMES.iter().find(|x| dir.join(x).is_file()) {
+        return Ok(name.into());
+    }
+
+    // Otherwise any script in the root that can be run directly
+    let mut candidates = vec![];
+    for entry in fs::read_dir(dir).context(format!("reading {dir:?}"))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "py")
+            && fs::read_to_string(&path).is_ok_and(|x| x.contains("__main__"))
+        {
+            candidates.push(path.strip_prefix(dir)?.to_owned());
+        }
+    }
+    candidates.sort();
+    match candidates.as_slice() {
+        [] => Err(anyhow!(
+            "Couldn't find the entry .py file of {dir:?}, expected one of {}",
+            PYTHON_ENTRY_NAMES.join(", ")
+        )),
+        [entry] => Ok(entry.clone()),
+        _ => {
+            warn!(
+                "Found multiple possible entry files ({candidates:?}), using {:?}, change `entry_file` in bob.toml if that's wrong",
+                candidates[0]
+            );
+            Ok(candidates[0].clone())
+        }
+    }
+}
+
+/// Finds RLBot bot configs, tomls with a `[settings]` table, relative to `dir`
+fn find_bot_tomls(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut bot_tomls = vec![];
+    for result in ignore::WalkBuilder::new(dir)
+        .hidden(true)
+        .git_ignore(true)
+        .build()
+    {
+        let path = result?.into_path();
+        if !path.is_file() || path.extension().is_none_or(|x| x != "toml") {
+            continue;
+        }
+        let is_bot_toml = fs::read_to_string(&path)
+            .ok()
+            .and_then(|x| toml::from_str::<toml::Table>(&x).ok())
+            .is_some_and(|x| x.get("settings").is_some_and(|x| x.is_table()));
+        if is_bot_toml {
+            bot_tomls.push(path.strip_prefix(dir)?.to_owned());
+        }
+    }
+    bot_tomls.sort();
+    Ok(bot_tomls)
+}
+
+/// Adds `bob_toml_path` to the `dependencies` of the closest bob.toml in a parent directory
+fn register_in_parent(bob_toml_path: &Path) -> anyhow::Result<()> {
+    let project_dir = bob_toml_path.parent().unwrap();
+    let parent_bob_toml_path = project_dir
+        .ancestors()
+        .skip(1)
+        .map(|x| x.join("bob.toml"))
+        .find(|x| x.is_file())
+        .context("No bob.toml found in a parent directory to register the project in")?;
+    let parent_dir = parent_bob_toml_path.parent().unwrap();
+
+    let dependency = bob_toml_path.strip_prefix(parent_dir)?;
+    let dependency_str = dependency
+        .to_str()
+        .context("Path of the new bob.toml isn't valid UTF-8")?
+        .replace('\\', "/");
+
+    let mut parent_bob_toml: toml_edit::DocumentMut = fs::read_to_string(&parent_bob_toml_path)
+        .context(format!("reading {parent_bob_toml_path:?}"))?
+        .parse()
+        .context(format!("parsing {parent_bob_toml_path:?}"))?;
+    let dependencies = parent_bob_toml
+        .entry("dependencies")
+        .or_insert_with(|| toml_edit::value(toml_edit::Array::new()))
+        .as_array_mut()
+        .context(format!(
+            "`dependencies` in {parent_bob_toml_path:?} isn't an array"
+        ))?;
+
+    // The dependency might already be matched by a glob
+    let already_registered = dependencies.iter().filter_map(|x| x.as_str()).any(|x| {
+        glob::Pattern::new(x).is_ok_and(|x| x.matches_path(dependency))
+            || Path::new(x) == dependency
+    });
+    if already_registered {
+        info!("{parent_bob_toml_path:?} already includes {dependency_str:?}");
+        return Ok(());
+    }
+
+    dependencies.push(dependency_str.as_str());
+    fs::write(&parent_bob_toml_path, parent_bob_toml.to_string())
+        .context(format!("Couldn't write {parent_bob_toml_path:?}"))?;
+    info!("Registered {dependency_str:?} in {parent_bob_toml_path:?}");
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use anyhow::{anyhow, Context};
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+/// Variables from env files, looked up when the environment doesn't have them
+pub type Variables = BTreeMap<String, String>;
+
+/// Replaces `${VAR}` and `${VAR:-default}` in all strings of `table`, `$$` is a literal `$`.
+/// Variables come from the environment, then `variables`. Returns whether anything changed
+pub fn interpolate_table(table: &mut toml::Table, variables: &Variables) -> anyhow::Result<bool> {
+    let mut changed = false;
+    for (key, value) in table.iter_mut() {
+        changed |= interpolate_value(value, key, variables)?;
+    }
+    Ok(changed)
+}
+
+/// `key_path` is only used for errors, like `config[0].build_args.VERSION`
+fn interpolate_value(
+    value: &mut toml::Value,
+    key_path: &str,
+    variables: &Variables,
+) -> anyhow::Result<bool> {
+    match value {
+        toml::Value::String(s) => {
+            let interpolated = interpolate_str(s, variables).context(format!("in `{key_path}`"))?;
+            let changed = interpolated != *s;
+            *s = interpolated;
+            Ok(changed)
+        }
+        toml::Value::Array(array) => {
+            let mut changed = false;
+            for (i, value) in array.iter_mut().enumerate() {
+                changed |= interpolate_value(value, &format!("{key_path}[{i}]"), variables)?;
+            }
+            Ok(changed)
+        }
+        toml::Value::Table(table) => {
+            let mut changed = false;
+            for (key, value) in table.iter_mut() {
+                changed |= interpolate_value(value, &format!("{key_path}.{key}"), variables)?;
+            }
+            Ok(changed)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn interpolate_str(s: &str, variables: &Variables) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated `${{` in {s:?}"))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            let value = env::var(name)
+                .ok()
+                .or_else(|| variables.get(name).cloned())
+                .filter(|x| !x.is_empty() || default.is_none());
+            match (value, default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(anyhow!(
+                        "variable {name:?} isn't set, set it or use `${{{name}:-default}}`"
+                    ))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Reads `KEY=VALUE` lines, ignoring empty lines and `#` comments. Values may be quoted
+pub fn read_env_file(path: &Path) -> anyhow::Result<Variables> {
+    let content = fs::read_to_string(path).context(format!("reading env file {path:?}"))?;
+    let mut variables = Variables::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {} of env file {path:?} isn't KEY=VALUE", i + 1))?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .into_iter()
+            .find_map(|quote| value.strip_prefix(quote)?.strip_suffix(quote))
+            .unwrap_or(value);
+        variables.insert(key.trim().to_owned(), value.to_owned());
+    }
+    Ok(variables)
+}
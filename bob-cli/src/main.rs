@@ -1,13 +1,22 @@
-use std::path::PathBuf;
+use std::{num::NonZeroUsize, path::PathBuf};
 
 use bob_lib::dirhasher;
+use build::runtime::RuntimeKind;
 use clap::{Parser, Subcommand};
+use units::parse_byte_size;
 
 mod build;
 mod buildinfo;
+mod check;
 mod config;
 mod diff;
+mod gc;
+mod init;
+mod interpolate;
+mod platform;
+mod render;
 mod split;
+mod units;
 
 #[derive(Parser)]
 struct Cli {
@@ -22,25 +31,158 @@ enum Command {
     /// Build based on a bob.toml
     Build(BuildCommand),
 
+    /// Validate a bob.toml, its dependencies and the bot configs they reference without
+    /// building anything
+    Check { config_path: PathBuf },
+
+    /// Inspect bob.toml configs
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
+    /// Remove images and outputs that no project of the config tree uses anymore
+    Gc(GcCommand),
+
+    /// Write a bob.toml for an existing bot project, detecting how it should be built
+    Init {
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+        /// Name of the project, defaults to the name of the directory
+        #[arg(long)]
+        name: Option<String>,
+        /// Add the new bob.toml to the `dependencies` of the closest bob.toml in a parent directory
+        #[arg(long)]
+        register: bool,
+        /// Overwrite an existing bob.toml
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Print the generated Dockerfile of a project, the values it was rendered with are
+    /// printed to stderr
+    Render {
+        config_path: PathBuf,
+        project_name: String,
+    },
+
     /// Split bob build directory into platform-specific directories
-    Split { dir: PathBuf },
+    Split {
+        dir: PathBuf,
+        /// Platform to split into, with optional fallbacks used when a project has no native
+        /// binaries, e.g. `aarch64-linux:x86_64-linux,x86_64-windows`. Can be repeated, defaults
+        /// to `x86_64-linux:x86_64-windows` and `x86_64-windows`
+        #[arg(long = "platform", value_name = "PLATFORM[:FALLBACK,...]")]
+        platforms: Vec<split::PlatformSpec>,
+    },
 
     /// Diffing tool for directories, based on qbsdiff. Outputs a diff to stdout
     Diff { old: PathBuf, new: PathBuf },
     /// Diffing tool for directories, based on qbsdiff. Reads a diff from stdin and applies it
     DiffApply { dir: PathBuf },
 
+    /// Print a JSON Schema of bob.toml, for autocompletion and validation in editors
+    Schema,
+
     /// Generate a hash for a directory, the same function is used internally for incremental
     /// builds.
     Hash { dir: PathBuf },
 }
 
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective config of a project, with the `[defaults]` it inherits filled in
+    Show {
+        project_name: String,
+        #[arg(short, long, default_value = "./bob.toml")]
+        config_path: PathBuf,
+    },
+}
+
 #[derive(Parser, Debug)]
 struct BuildCommand {
     config_path: PathBuf,
     #[arg(short, long, default_value = "./bob_build")]
     /// By default, bob will reuse already-built projects if the project hash matches
     out_dir: PathBuf,
+    /// Container runtime used to build projects, overrides the one set in bob.toml
+    #[arg(long, env = "BOB_CONTAINER_RUNTIME", value_enum)]
+    runtime: Option<RuntimeKind>,
+    /// How many projects to build at the same time
+    #[arg(short, long, default_value = "1")]
+    jobs: NonZeroUsize,
+    /// Max size of the tar a single project's container may output, e.g. `512M` or `8G`
+    #[arg(long, default_value = "16G", value_parser = parse_byte_size)]
+    max_output_size: u64,
+    /// Max amount of files, dirs and links in the tar a single project's container may output
+    #[arg(long, default_value = "100000")]
+    max_output_entries: u64,
+    /// Keep building other projects when one fails, failed projects keep their previous output
+    #[arg(short, long)]
+    keep_going: bool,
+    /// Only build projects whose name matches one of these globs, can be repeated
+    #[arg(long, value_name = "GLOB", value_parser = glob::Pattern::new)]
+    only: Vec<glob::Pattern>,
+    /// Don't build projects whose name matches one of these globs, can be repeated
+    #[arg(long, value_name = "GLOB", value_parser = glob::Pattern::new)]
+    exclude: Vec<glob::Pattern>,
+    /// Rebuild the selected projects even if their cache key matches
+    #[arg(short, long)]
+    force: bool,
+    /// How many lines of a failed project's log to print, logs are written to `.logs` in
+    /// `out_dir`
+    #[arg(long, default_value = "20")]
+    log_tail: usize,
+    /// How long building and running a project's image may take, like `30m` or `1h30m`. Used
+    /// for projects that don't set `timeout` in bob.toml
+    #[arg(long, value_name = "DURATION")]
+    timeout: Option<units::HumanDuration>,
+    /// How many CPUs a project's containers may use, like `1.5`. Used for projects that don't
+    /// set `cpus` in bob.toml
+    #[arg(long)]
+    cpus: Option<f64>,
+    /// How much memory a project's containers may use, like `512M` or `4G`. Used for projects
+    /// that don't set `memory` in bob.toml
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    memory: Option<u64>,
+    /// Remove the previous image of every rebuilt project
+    #[arg(long)]
+    prune_images: bool,
+    /// Only print which projects would be rebuilt and why, without building anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Also print the generated Dockerfile of every project in the dry run
+    #[arg(long, requires = "dry_run")]
+    show_dockerfile: bool,
+}
+
+#[derive(Parser, Debug)]
+struct GcCommand {
+    config_path: PathBuf,
+    #[arg(short, long, default_value = "./bob_build")]
+    out_dir: PathBuf,
+    /// Container runtime the images were built with, overrides the one set in bob.toml
+    #[arg(long, env = "BOB_CONTAINER_RUNTIME", value_enum)]
+    runtime: Option<RuntimeKind>,
+    /// How many of the newest images to keep per project. The images of the builds in
+    /// `out_dir` are always kept
+    #[arg(long, default_value = "1")]
+    keep: usize,
+    /// Only print what would be removed
+    #[arg(long)]
+    dry_run: bool,
+}
+
+impl BuildCommand {
+    /// Whether a project is selected by `--only` and `--exclude`
+    fn selects(&self, project_name: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|x| x.matches(project_name)))
+            && !self.exclude.iter().any(|x| x.matches(project_name))
+    }
+}
+
+fn command_schema() -> anyhow::Result<()> {
+    let schema = schemars::schema_for!(config::RootConfig);
+    println!("{}", serde_json::to_string_pretty(&schema)?);
+    Ok(())
 }
 
 fn command_hash(dir: PathBuf) -> anyhow::Result<()> {
@@ -54,9 +196,26 @@ fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     match cli.command {
         Command::Build(x) => build::command_build(x),
-        Command::Split { dir } => split::command_split(dir),
+        Command::Check { config_path } => check::command_check(config_path),
+        Command::Config(ConfigCommand::Show {
+            project_name,
+            config_path,
+        }) => config::command_config_show(config_path, &project_name),
+        Command::Gc(x) => gc::command_gc(x),
+        Command::Init {
+            dir,
+            name,
+            register,
+            force,
+        } => init::command_init(dir, name, register, force),
+        Command::Render {
+            config_path,
+            project_name,
+        } => render::command_render(config_path, &project_name),
+        Command::Split { dir, platforms } => split::command_split(dir, platforms),
         Command::Diff { old, new } => diff::command_diff(old, new),
         Command::DiffApply { dir } => diff::command_diff_apply(dir),
+        Command::Schema => command_schema(),
         Command::Hash { dir } => command_hash(dir),
     }
 }
@@ -0,0 +1,181 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Windows,
+    Linux,
+}
+
+impl Os {
+    /// Whether a project must ship binaries for this OS. Projects that don't declare their
+    /// platforms must ship windows binaries, linux ones are optional
+    pub fn is_required(self, declared_platforms: Option<&[Os]>) -> bool {
+        match declared_platforms {
+            Some(platforms) => platforms.contains(&self),
+            None => self == Os::Windows,
+        }
+    }
+
+    /// Whether a project may ship binaries for this OS
+    pub fn is_allowed(self, declared_platforms: Option<&[Os]>) -> bool {
+        declared_platforms.is_none_or(|x| x.contains(&self))
+    }
+
+    /// The bot.toml setting holding the run command for this OS
+    pub fn run_command_key(self) -> &'static str {
+        match self {
+            Self::Windows => "run_command",
+            Self::Linux => "run_command_linux",
+        }
+    }
+}
+
+impl Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Windows => "windows",
+            Self::Linux => "linux",
+        })
+    }
+}
+
+impl FromStr for Os {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "windows" => Ok(Self::Windows),
+            "linux" => Ok(Self::Linux),
+            _ => Err(anyhow!("unknown OS {s:?}, expected windows or linux")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Arch {
+    X86_64,
+    X86,
+    Aarch64,
+}
+
+impl Display for Arch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::X86_64 => "x86_64",
+            Self::X86 => "x86",
+            Self::Aarch64 => "aarch64",
+        })
+    }
+}
+
+impl FromStr for Arch {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" | "amd64" => Ok(Self::X86_64),
+            "x86" | "i386" | "i586" | "i686" => Ok(Self::X86),
+            "aarch64" | "arm64" => Ok(Self::Aarch64),
+            _ => Err(anyhow!(
+                "unknown architecture {s:?}, expected x86_64, x86 or aarch64"
+            )),
+        }
+    }
+}
+
+/// A target triple like `x86_64-unknown-linux-gnu`, as used for the `[PLATFORM]` directories
+/// in the tar output of bob Dockerfiles.
+///
+/// Only the architecture (first component) and the OS are required, so `x86_64-linux` is valid
+/// too. The vendor is ignored.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TargetTriple {
+    pub arch: Arch,
+    pub os: Os,
+    /// Whatever comes after the OS, like `gnu` or `msvc`
+    pub abi: Option<String>,
+}
+
+impl TargetTriple {
+    pub fn platform(&self) -> Platform {
+        Platform {
+            arch: self.arch,
+            os: self.os,
+        }
+    }
+
+    /// The target triple of the `[PLATFORM]` directory a built file is in
+    pub fn of_built_file(relative_path: &Path) -> Option<Self> {
+        relative_path
+            .components()
+            .next()?
+            .as_os_str()
+            .to_str()?
+            .parse()
+            .ok()
+    }
+}
+
+impl FromStr for TargetTriple {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut components = s.split('-');
+        let arch = components
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .context(format!("invalid target triple {s:?}"))?;
+        let os = components
+            .by_ref()
+            .find_map(|x| x.parse().ok())
+            .ok_or_else(|| anyhow!("invalid target triple {s:?}, no known OS in it"))?;
+        let abi = components.collect::<Vec<_>>().join("-");
+
+        Ok(Self {
+            arch,
+            os,
+            abi: (!abi.is_empty()).then_some(abi),
+        })
+    }
+}
+
+/// An architecture and OS combination, like `aarch64-linux`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Platform {
+    pub arch: Arch,
+    pub os: Os,
+}
+
+impl Platform {
+    /// The bot.toml setting holding the run command for this exact platform, e.g.
+    /// `run_command_linux_aarch64`
+    pub fn run_command_key(self) -> String {
+        format!("{}_{}", self.os.run_command_key(), self.arch)
+    }
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.arch, self.os)
+    }
+}
+
+impl FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<TargetTriple>()
+            .context(format!(
+                "invalid platform {s:?}, expected something like x86_64-linux"
+            ))?
+            .platform())
+    }
+}
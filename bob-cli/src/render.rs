@@ -0,0 +1,33 @@
+use anyhow::{anyhow, Context};
+use std::{fs, path::PathBuf};
+
+use crate::build::{bin_builder, project_src_root};
+use crate::config::{find_project, read_build_configs, BuilderConfigVariant};
+
+pub fn command_render(config_path: PathBuf, project_name: &str) -> anyhow::Result<()> {
+    if !fs::exists(&config_path)? {
+        return Err(anyhow!("File doesn't exist"));
+    }
+
+    let build_configs = read_build_configs(config_path)?;
+    let (bob_toml_path, build_config) = find_project(&build_configs, project_name)?;
+
+    let project_root = project_src_root(bob_toml_path)?;
+
+    let values = bin_builder::template_values(build_config)?;
+    let values_kind = match build_config.builder_config {
+        BuilderConfigVariant::Custom(_) => "Resolved custom values",
+        _ => "Builder config values",
+    };
+    let values_toml = toml::to_string_pretty(&values).unwrap_or_else(|_| format!("{values:?}\n"));
+    eprintln!("# {values_kind} available to the template:");
+    for line in values_toml.lines() {
+        eprintln!("#   {line}");
+    }
+
+    let dockerfile = bin_builder::generate_dockerfile(build_config, &project_root)
+        .context(format!("Rendering Dockerfile of project {project_name}"))?;
+    print!("{dockerfile}");
+
+    Ok(())
+}
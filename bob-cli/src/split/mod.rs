@@ -1,48 +1,150 @@
 use anyhow::{anyhow, Context};
-use glob::glob;
-use log::{error, warn};
+use log::{error, info, warn};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 use crate::build::BUILDINFO_PATH_RELATIVE;
 use crate::buildinfo::BuildInfo;
+use crate::platform::{Arch, Os, Platform, TargetTriple};
 
-struct Platform {
-    name: &'static str,
-    target_glob: &'static str,
-    fallback_glob: Option<&'static str>,
+/// A platform to split into, and the platforms whose binaries may be used (e.g. through
+/// emulation) when a project has no native binaries, in order of preference.
+///
+/// Parsed from `PLATFORM[:FALLBACK,...]`, like `aarch64-linux:x86_64-linux,x86_64-windows`
+#[derive(Debug, Clone)]
+pub struct PlatformSpec {
+    pub platform: Platform,
+    pub fallbacks: Vec<Platform>,
 }
 
-const PLATFORMS: &[Platform] = &[
-    Platform {
-        name: "x86_64-linux",
-        target_glob: "*x86*linux*",
-        fallback_glob: Some("*x86*windows*"),
-    },
-    // arm example:
-    // Platform {
-    //     name: "aarch64 linux",
-    //     target_glob: "*(aarch64|x86)*linux*",
-    //     fallback_glob: Some("*(aarch64|x86)*windows*"),
-    // },
-    Platform {
-        name: "x86_64-windows",
-        target_glob: "*x86*windows*",
-        fallback_glob: None,
-    },
-];
-
-pub fn command_split(src_dir: PathBuf) -> anyhow::Result<()> {
+impl FromStr for PlatformSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (platform, fallbacks) = s.split_once(':').unwrap_or((s, ""));
+        Ok(Self {
+            platform: platform.parse()?,
+            fallbacks: fallbacks
+                .split(',')
+                .filter(|x| !x.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Used when no platforms are passed to `bob split`
+pub fn default_platforms() -> Vec<PlatformSpec> {
+    let x86_64_linux = Platform {
+        arch: Arch::X86_64,
+        os: Os::Linux,
+    };
+    let x86_64_windows = Platform {
+        arch: Arch::X86_64,
+        os: Os::Windows,
+    };
+    vec![
+        PlatformSpec {
+            platform: x86_64_linux,
+            fallbacks: vec![x86_64_windows],
+        },
+        PlatformSpec {
+            platform: x86_64_windows,
+            fallbacks: vec![],
+        },
+    ]
+}
+
+/// The `[PLATFORM]` dirs of a built project for the given platform
+fn platform_dirs(src_proj_dir: &Path, platform: Platform) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    for entry in fs::read_dir(src_proj_dir).context("failed to read project directory")? {
+        let entry = entry.context("failed to read path")?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let dir_name = entry.file_name();
+        match dir_name.to_str().map(TargetTriple::from_str) {
+            Some(Ok(triple)) if triple.platform() == platform => dirs.push(entry.path()),
+            Some(Ok(_)) => {}
+            _ => warn!(
+                "{:?} isn't a target triple, it won't be included for any platform",
+                entry.path()
+            ),
+        }
+    }
+    Ok(dirs)
+}
+
+/// Points the run command of a bot.toml at the binary of `platform`, if the bot.toml was built
+/// with per-arch run commands. The run command has to point at a file in `dst_proj_dir`
+fn select_run_command(
+    bot_toml_path: &Path,
+    dst_proj_dir: &Path,
+    platform: Platform,
+) -> anyhow::Result<()> {
+    let Ok(mut toml_bot_config) = fs::read_to_string(bot_toml_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|x| Ok(toml::from_str::<toml::Table>(&x)?))
+    else {
+        return Ok(()); // not a bot.toml
+    };
+    let Some(settings_table) = toml_bot_config
+        .get_mut("settings")
+        .and_then(|x| x.as_table_mut())
+    else {
+        return Ok(());
+    };
+    let per_arch_run_command = settings_table.get(&platform.run_command_key()).cloned();
+    if let Some(run_command) = &per_arch_run_command {
+        settings_table.insert(
+            platform.os.run_command_key().to_owned(),
+            run_command.clone(),
+        );
+    }
+
+    // Bot tomls built by older versions of bob only have a run command for one arch
+    if let Some(run_command) = settings_table
+        .get(platform.os.run_command_key())
+        .and_then(|x| x.as_str())
+    {
+        if !dst_proj_dir.join(run_command.replace('\\', "/")).is_file() {
+            return Err(anyhow!(
+                "{} in {bot_toml_path:?} points at {run_command:?}, which isn't a {platform} binary. Rebuild the project so it has run commands per architecture",
+                platform.os.run_command_key()
+            ));
+        }
+    }
+
+    if per_arch_run_command.is_some() {
+        fs::write(bot_toml_path, toml_bot_config.to_string())
+            .context(format!("failed to write {bot_toml_path:?}"))?;
+    }
+    Ok(())
+}
+
+pub fn command_split(src_dir: PathBuf, platforms: Vec<PlatformSpec>) -> anyhow::Result<()> {
     if !fs::exists(&src_dir)? {
         return Err(anyhow!("Directory doesn't exist"));
     }
 
-    for platform in PLATFORMS {
+    let platforms = if platforms.is_empty() {
+        default_platforms()
+    } else {
+        platforms
+    };
+
+    // Projects that declared a platform in bob.toml but have no binaries for it
+    let mut missing_platforms = vec![];
+
+    for spec in &platforms {
+        let platform = spec.platform;
         let dir_name_str = src_dir.as_path().file_name().unwrap();
         let dst_dir = src_dir.parent().unwrap().join(format!(
             "{}_{}",
             dir_name_str.to_str().unwrap(),
-            platform.name
+            platform
         ));
 
         if fs::read_dir(&dst_dir)
@@ -86,58 +188,80 @@ pub fn command_split(src_dir: PathBuf) -> anyhow::Result<()> {
                 return Err(anyhow!("Project directory doesn't exist: {src_proj_dir:?}"));
             }
 
-            let final_glob = format!(
-                "{}/{}",
-                src_proj_dir.as_os_str().to_str().unwrap(),
-                platform.target_glob
-            );
-            let mut src_proj_target_dirs = glob(&final_glob)
-                .context(format!("invalid glob pattern: {final_glob}"))?
-                .collect::<Vec<_>>();
+            // Projects that declare their platforms intentionally leave out the others
+            let declared_platforms = project.platforms.as_deref();
+            let declared = declared_platforms.is_some();
+
+            let mut resolved_platform = platform;
+            // Binaries of an undeclared OS would end up without a run command in the bot.toml
+            let mut src_proj_target_dirs = if platform.os.is_allowed(declared_platforms) {
+                platform_dirs(&src_proj_dir, platform)?
+            } else {
+                vec![]
+            };
 
             if src_proj_target_dirs.is_empty() {
-                if let Some(fallback_glob) = platform.fallback_glob {
-                    warn!(
-                        "project {} has no matching native binaries, falling back to {}",
-                        project.name,
-                        platform.fallback_glob.unwrap_or("none")
+                let has_os_binaries = fs::read_dir(&src_proj_dir)?
+                    .filter_map(|x| x.ok()?.file_name().to_str()?.parse::<TargetTriple>().ok())
+                    .any(|x| x.os == platform.os);
+                if declared && platform.os.is_required(declared_platforms) && !has_os_binaries {
+                    error!(
+                        "project {} declares platform {} but has no {} binaries at all",
+                        project.name, platform.os, platform.os
                     );
+                    missing_platforms.push(format!("{} ({platform})", project.name));
+                    continue; // with other projects, report all of them at the end
+                }
+
+                for &fallback in spec
+                    .fallbacks
+                    .iter()
+                    .filter(|x| x.os.is_allowed(declared_platforms))
+                {
+                    src_proj_target_dirs = platform_dirs(&src_proj_dir, fallback)?;
+                    if src_proj_target_dirs.is_empty() {
+                        continue;
+                    }
+                    if declared {
+                        info!(
+                            "project {} has no native binaries for {platform}, falling back to {fallback}",
+                            project.name
+                        );
+                    } else {
+                        warn!(
+                            "project {} has no matching native binaries, falling back to {fallback}",
+                            project.name
+                        );
+                    }
+                    resolved_platform = fallback;
+                    break;
+                }
+            }
 
-                    let final_glob = format!(
-                        "{}/{}",
-                        src_proj_dir.as_os_str().to_str().unwrap(),
-                        fallback_glob
+            if src_proj_target_dirs.is_empty() {
+                if declared {
+                    info!(
+                        "project {} doesn't declare platform {} and has no fallback binaries, skipping it for platform {platform}",
+                        project.name, platform.os
                     );
-                    src_proj_target_dirs = glob(&final_glob)
-                        .context(format!("invalid glob pattern: {final_glob}"))?
-                        .collect::<Vec<_>>();
                 } else {
                     error!(
-                        "project {} has no matching native binaries, no fallback glob found platform {}",
-                        project.name,
-                        platform.name
+                        "project {} has no matching native or fallback binaries for platform {platform}",
+                        project.name
                     );
-                    continue; // with other projects, don't halt the entire splitting
                 }
-            }
-
-            if src_proj_target_dirs.is_empty() {
-                error!(
-                    "project {} has no matching native or fallback binaries for platform {}",
-                    project.name, platform.name
-                );
                 continue; // with other projects, don't halt the entire splitting
             }
 
             for src_proj_target_dir in src_proj_target_dirs {
-                let src_proj_target_dir = src_proj_target_dir.context("globbing failed")?;
                 let dst_proj_target_dir = dst_proj_dir.join(
                     src_proj_target_dir
                         .file_name()
                         .ok_or_else(|| anyhow!("invalid file name"))?,
                 );
-                copy_dir_all(src_proj_target_dir, dst_proj_target_dir)
-                    .context("couldn't copy {src_proj_target_dir:?} to {dst_proj_target_dir:?}")?;
+                copy_dir_all(&src_proj_target_dir, &dst_proj_target_dir).context(format!(
+                    "couldn't copy {src_proj_target_dir:?} to {dst_proj_target_dir:?}"
+                ))?;
             }
 
             // Copy other files in the root of the project dir
@@ -153,12 +277,22 @@ pub fn command_split(src_dir: PathBuf) -> anyhow::Result<()> {
                 if !path.file_type()?.is_file() {
                     continue;
                 }
-                fs::copy(path.path(), dst_proj_dir.join(path.file_name()))
-                    .context("failed to copy file")?;
+                let dst_path = dst_proj_dir.join(path.file_name());
+                fs::copy(path.path(), &dst_path).context("failed to copy file")?;
+                if dst_path.extension().is_some_and(|x| x == "toml") {
+                    select_run_command(&dst_path, &dst_proj_dir, resolved_platform)?;
+                }
             }
         }
     }
 
+    if !missing_platforms.is_empty() {
+        return Err(anyhow!(
+            "Projects are missing binaries for platforms they declare: {}",
+            missing_platforms.join(", ")
+        ));
+    }
+
     Ok(())
 }
 
@@ -169,6 +303,13 @@ fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> std::io::Result
         let ty = entry.file_type()?;
         if ty.is_dir() {
             copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
+        } else if ty.is_symlink() {
+            // Symlinks were checked to stay inside the project when building, keep them as is
+            let target = fs::read_link(entry.path())?;
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, dst.as_ref().join(entry.file_name()))?;
+            #[cfg(windows)]
+            std::os::windows::fs::symlink_file(target, dst.as_ref().join(entry.file_name()))?;
         } else {
             fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
         }
@@ -0,0 +1,139 @@
+use std::{fmt::Display, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Parses sizes like `1024`, `512K`, `512M` or `8G` (powers of 1024)
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        x if x.ends_with('K') => (&s[..x.len() - 1], 1 << 10),
+        x if x.ends_with('M') => (&s[..x.len() - 1], 1 << 20),
+        x if x.ends_with('G') => (&s[..x.len() - 1], 1 << 30),
+        x if x.ends_with('T') => (&s[..x.len() - 1], 1 << 40),
+        x => (&s[..x.len()], 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|x| x.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {s:?}, expected something like 512M or 8G"))
+}
+
+/// A size in bytes, written like `512M` or `8G`, see [`parse_byte_size`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_byte_size(s).map(Self)
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ByteSize> for String {
+    fn from(x: ByteSize) -> Self {
+        x.to_string()
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The largest unit that fits exactly
+        for (unit, multiplier) in [
+            ("T", 1u64 << 40),
+            ("G", 1 << 30),
+            ("M", 1 << 20),
+            ("K", 1 << 10),
+        ] {
+            if self.0 != 0 && self.0 % multiplier == 0 {
+                return write!(f, "{}{unit}", self.0 / multiplier);
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A duration written like `90s`, `30m` or `1h30m`, a plain number is in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HumanDuration(pub Duration);
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid duration {s:?}, expected something like 90s, 30m or 1h30m");
+        let s = s.trim();
+        if let Ok(secs) = s.parse::<u64>() {
+            return Ok(Self(Duration::from_secs(secs)));
+        }
+
+        let mut secs = 0u64;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let unit_start = rest
+                .find(|x: char| !x.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let number = rest[..unit_start].parse::<u64>().map_err(|_| invalid())?;
+            let unit = rest[unit_start..].chars().next().unwrap();
+            let multiplier = match unit {
+                'h' => 60 * 60,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(invalid()),
+            };
+            secs = number
+                .checked_mul(multiplier)
+                .and_then(|x| x.checked_add(secs))
+                .ok_or_else(invalid)?;
+            rest = &rest[unit_start + unit.len_utf8()..];
+        }
+        if s.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self(Duration::from_secs(secs)))
+    }
+}
+
+impl TryFrom<String> for HumanDuration {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<HumanDuration> for String {
+    fn from(x: HumanDuration) -> Self {
+        x.to_string()
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.0.as_secs();
+        let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if hours > 0 {
+            write!(f, "{hours}h")?;
+        }
+        if minutes > 0 {
+            write!(f, "{minutes}m")?;
+        }
+        if secs > 0 || hours == 0 && minutes == 0 {
+            write!(f, "{secs}s")?;
+        }
+        Ok(())
+    }
+}
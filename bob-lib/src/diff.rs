@@ -103,7 +103,7 @@ impl DirDiff {
                     });
                 }
 
-                if old_file.len() == 0 {
+                if old_file.is_empty() {
                     return Some(DirDiffEntry::File {
                         path: relative_path,
                         state: DataState::Raw(new_file.into()),
@@ -1,7 +1,7 @@
 use std::{
     fs,
     hash::{Hash as _, Hasher as _},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use anyhow::Context as _;
@@ -12,7 +12,10 @@ pub mod bobdiff {
     pub use crate::diff::*;
 }
 
-pub fn dirhasher(dir: PathBuf) -> anyhow::Result<u64> {
+/// The files of `dir` that matter for building it, relative to `dir` and sorted. Hidden and
+/// gitignored files are left out. Symlinks to files are listed at their own path, not their
+/// target's
+pub fn source_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
     let dir = dir.canonicalize()?;
 
     let mut paths = vec![];
@@ -24,17 +27,23 @@ pub fn dirhasher(dir: PathBuf) -> anyhow::Result<u64> {
     {
         let path = result?.into_path();
         if path.is_file() {
-            paths.push(path)
+            paths.push(path.strip_prefix(&dir)?.to_owned())
         }
     }
 
     paths.sort();
 
+    Ok(paths)
+}
+
+pub fn dirhasher(dir: PathBuf) -> anyhow::Result<u64> {
+    let dir = dir.canonicalize()?;
+
     let mut hasher = RapidInlineHasher::default();
 
-    for path in paths {
-        let content = fs::read(&path).context("hasher couldn't read file")?;
-        path.canonicalize()?.strip_prefix(&dir)?.hash(&mut hasher);
+    for path in source_files(&dir)? {
+        let content = fs::read(dir.join(&path)).context("hasher couldn't read file")?;
+        path.hash(&mut hasher);
         content.hash(&mut hasher);
     }
 